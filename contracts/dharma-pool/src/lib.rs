@@ -1,5 +1,129 @@
 #![no_std]
-use soroban_sdk::{contract, contractimpl, contracttype, Address, BytesN, Env, String, Vec};
+use soroban_sdk::{
+    contract, contractclient, contracterror, contractimpl, contracttype,
+    crypto::bls12_381::{Fr, G1Affine, G2Affine},
+    symbol_short, token, vec,
+    xdr::ToXdr,
+    Address, BytesN, Env, Error, String, Vec, U256,
+};
+
+/// A stake's share of a rail, as returned by the Identity Pool
+#[contracttype]
+#[derive(Clone)]
+pub struct Backing {
+    pub staker: Address,
+    pub amount: i128,
+}
+
+/// The subset of the SBT contract the Dharma Pool relies on
+#[contractclient(name = "SbtClient")]
+pub trait SbtInterface {
+    fn get_principal(env: Env, agent: Address) -> Option<Address>;
+}
+
+/// The subset of the Identity Pool the Dharma Pool relies on
+#[contractclient(name = "IdentityPoolClient")]
+pub trait IdentityPoolInterface {
+    fn allocate(env: Env, amount: i128, agent: Address, duration: u64) -> Result<Vec<Backing>, Error>;
+    fn release_allocation(env: Env, agent: Address, backings: Vec<Backing>) -> Result<(), Error>;
+    fn reallocate(env: Env, agent: Address, from: Address, amount: i128, duration: u64) -> Result<Vec<Backing>, Error>;
+    fn distribute_fees(env: Env, rail_id: BytesN<32>, amount: i128, backings: Vec<Backing>) -> Result<(), Error>;
+    fn get_collateral_token(env: Env) -> Option<Address>;
+}
+
+/// What happens to a leaving staker's rails
+#[contracttype]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitPolicy {
+    Revoke,                     // Revoke every rail the staker backs
+    Reback,                     // Move the staker's share to other stakes, revoking rails that cannot be re-backed
+}
+
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct StakerExit {
+    pub revoked: u32,
+    pub rebacked: u32,
+    pub remaining: u32,         // Rails left for another call
+}
+
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct AgentRevocation {
+    pub revoked: u32,
+    pub remaining: u32,         // Rails left for another call
+}
+
+#[contracterror]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum RailError {
+    InvalidAmount = 1,
+    RailNotFound = 2,
+    RailNotActive = 3,
+    SpendingLimitExceeded = 4,
+    InvalidFeeSchedule = 5,
+    RailNotExpired = 6,
+    AlreadyReleased = 7,        // Backing already returned to the Identity Pool
+    InsufficientCapacity = 8,   // The Identity Pool could not back the rail
+    InvalidVerifyingKey = 9,
+    VerifierNotSet = 10,
+    InvalidProof = 11,
+    AttestationExpired = 12,
+    NonceUsed = 13,
+    Unauthorized = 14,
+    NoUnsettledFees = 15,
+    FeesRefused = 16,           // The Identity Pool still does not accept the rail's fees
+    StakerNotFlagged = 17,
+}
+
+/// Groth16 verifying key over BLS12-381, points uncompressed
+#[contracttype]
+#[derive(Clone)]
+pub struct VerifyingKey {
+    pub alpha: BytesN<96>,      // G1
+    pub beta: BytesN<192>,      // G2
+    pub gamma: BytesN<192>,     // G2
+    pub delta: BytesN<192>,     // G2
+    pub ic: Vec<BytesN<96>>,    // G1, one per public input plus one
+}
+
+#[contracttype]
+#[derive(Clone)]
+pub struct Groth16Proof {
+    pub a: BytesN<96>,          // G1
+    pub b: BytesN<192>,         // G2
+    pub c: BytesN<96>,          // G1
+    pub nonce: u64,             // Public input; single use per agent
+}
+
+/// Ed25519-signed statement from the registered verifier that an agent is compliant
+#[contracttype]
+#[derive(Clone)]
+pub struct Attestation {
+    pub expires_at: u64,
+    pub nonce: u64,             // Single use
+    pub signature: BytesN<64>,  // Over the XDR of the matching AttestationPayload
+}
+
+/// What the verifier signs
+#[contracttype]
+#[derive(Clone)]
+pub struct AttestationPayload {
+    pub dharma_pool: Address,   // This contract, so attestations cannot be replayed elsewhere
+    pub agent: Address,
+    pub amount: i128,
+    pub duration: u64,
+    pub expires_at: u64,
+    pub nonce: u64,
+}
+
+#[contracttype]
+#[derive(Clone)]
+pub enum ComplianceProof {
+    Groth16(Groth16Proof),
+    Attestation(Attestation),
+}
 
 #[contracttype]
 #[derive(Clone)]
@@ -11,19 +135,373 @@ pub struct ComplianceRail {
     pub used_amount: i128,
     pub is_active: bool,
     pub backing_stakers: Vec<Address>,
+    pub backing_amounts: Vec<i128>, // Capacity reserved from each backing staker, same order
+    pub capacity_released: bool,    // Backing returned to the Identity Pool
+    pub query_fee: i128,        // Paid by the agent at request time, in the fee token
+    pub revoked_by: Option<Address>,
+    pub revoke_reason: Option<String>,
+}
+
+#[contracttype]
+#[derive(Clone)]
+pub struct FeeSchedule {
+    pub token: Address,
+    pub base_fee: i128,         // Flat fee per rail request
+    pub rate_bps: u32,          // Plus this share of the requested amount
 }
 
 #[contracttype]
 pub enum DataKey {
     Rail(BytesN<32>),
+    Admin,
+    FeeSchedule,
+    VerifyingKey,
+    Guardian,               // Optional role allowed to revoke any rail
+    SbtContract,            // Resolves agent principals for revocation
+    AttestationKey,         // Ed25519 public key of the attestation verifier
+    UsedNonce(u64),
+    UsedProofNonce(Address, u64), // (Agent, nonce) of a consumed Groth16 proof
     IdentityPool,
     ProtocolTreasury,
     AgentRails(Address),  // Track rails by agent
+    StakerRailCount(Address),            // Live rails a staker backs, stored slot by slot
+    StakerRailAt(Address, u32),          // (Staker, slot) -> rail
+    StakerRailIndex(Address, BytesN<32>), // (Staker, rail) -> slot
     RailCount,
+    FlaggedStaker(Address), // Staker ejected by the Identity Pool -> when
+    UnsettledFees(BytesN<32>), // Staker fees of a rail the Identity Pool refused
+}
+
+const PROTOCOL_FEE_BPS: i128 = 1200; // 12%, the remaining 88% goes to stakers
+const BPS_DENOMINATOR: i128 = 10_000;
+const PUBLIC_INPUTS: u32 = 4;        // Agent, amount, duration, nonce
+const MAX_PAGE_SIZE: u32 = 100;
+const POOL_NO_BACKERS: u32 = 42;     // Identity Pool error when none of a rail's backers can still earn
+const MAX_EXIT_RAILS: u32 = 25;      // Rails handled per staker exit or kill switch call
+
+/// Public inputs a rail proof is bound to: sha256 of the agent's XDR, amount, duration and nonce
+fn public_inputs(env: &Env, agent: &Address, amount: i128, duration: u64, nonce: u64) -> Vec<Fr> {
+    let agent_hash = env.crypto().sha256(&agent.clone().to_xdr(env));
+    vec![
+        env,
+        Fr::from_bytes(agent_hash.to_bytes()),
+        Fr::from_u256(U256::from_u128(env, amount as u128)),
+        Fr::from_u256(U256::from_u128(env, duration as u128)),
+        Fr::from_u256(U256::from_u128(env, nonce as u128)),
+    ]
+}
+
+/// Check e(A, B) = e(alpha, beta) * e(vk_x, gamma) * e(C, delta)
+fn verify_groth16(env: &Env, vk: &VerifyingKey, proof: &Groth16Proof, inputs: &Vec<Fr>) -> bool {
+    let bls = env.crypto().bls12_381();
+
+    let mut points = Vec::new(env);
+    for i in 1..vk.ic.len() {
+        points.push_back(G1Affine::from_bytes(vk.ic.get(i).unwrap()));
+    }
+    let vk_x = bls.g1_add(&G1Affine::from_bytes(vk.ic.get(0).unwrap()), &bls.g1_msm(points, inputs.clone()));
+
+    let neg_a = -G1Affine::from_bytes(proof.a.clone());
+    bls.pairing_check(
+        vec![env, neg_a, G1Affine::from_bytes(vk.alpha.clone()), vk_x, G1Affine::from_bytes(proof.c.clone())],
+        vec![
+            env,
+            G2Affine::from_bytes(proof.b.clone()),
+            G2Affine::from_bytes(vk.beta.clone()),
+            G2Affine::from_bytes(vk.gamma.clone()),
+            G2Affine::from_bytes(vk.delta.clone()),
+        ],
+    )
+}
+
+/// Whether `caller` may revoke `rail`
+fn can_revoke(env: &Env, rail: &ComplianceRail, caller: &Address) -> bool {
+    if *caller == rail.agent || rail.backing_stakers.contains(caller) {
+        return true;
+    }
+
+    let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+    let guardian: Option<Address> = env.storage().instance().get(&DataKey::Guardian);
+    if *caller == admin || Some(caller.clone()) == guardian {
+        return true;
+    }
+
+    match env.storage().instance().get::<DataKey, Address>(&DataKey::SbtContract) {
+        Some(sbt_contract) => SbtClient::new(env, &sbt_contract).get_principal(&rail.agent) == Some(caller.clone()),
+        None => false,
+    }
+}
+
+fn staker_rail_count(env: &Env, staker: &Address) -> u32 {
+    env.storage().persistent().get(&DataKey::StakerRailCount(staker.clone())).unwrap_or(0)
+}
+
+/// Add a rail to the index of rails a staker backs
+fn index_rail(env: &Env, staker: &Address, rail_id: &BytesN<32>) {
+    let index_key = DataKey::StakerRailIndex(staker.clone(), rail_id.clone());
+    if env.storage().persistent().has(&index_key) {
+        return;
+    }
+    let count = staker_rail_count(env, staker);
+    env.storage().persistent().set(&DataKey::StakerRailAt(staker.clone(), count), rail_id);
+    env.storage().persistent().set(&index_key, &count);
+    env.storage().persistent().set(&DataKey::StakerRailCount(staker.clone()), &(count + 1));
+}
+
+/// Drop a rail from a staker's index by moving the last entry into its slot
+fn unindex_rail(env: &Env, staker: &Address, rail_id: &BytesN<32>) {
+    let index_key = DataKey::StakerRailIndex(staker.clone(), rail_id.clone());
+    let index: u32 = match env.storage().persistent().get(&index_key) {
+        Some(index) => index,
+        None => return,
+    };
+    let last = staker_rail_count(env, staker) - 1;
+
+    if index != last {
+        let moved: BytesN<32> = env.storage().persistent().get(&DataKey::StakerRailAt(staker.clone(), last)).unwrap();
+        env.storage().persistent().set(&DataKey::StakerRailAt(staker.clone(), index), &moved);
+        env.storage().persistent().set(&DataKey::StakerRailIndex(staker.clone(), moved), &index);
+    }
+    env.storage().persistent().remove(&DataKey::StakerRailAt(staker.clone(), last));
+    env.storage().persistent().remove(&index_key);
+    if last == 0 {
+        env.storage().persistent().remove(&DataKey::StakerRailCount(staker.clone()));
+    } else {
+        env.storage().persistent().set(&DataKey::StakerRailCount(staker.clone()), &last);
+    }
+}
+
+/// Move the backing at `index` of a live rail onto other stakes; false if the
+/// Identity Pool cannot cover it
+fn reback_rail(env: &Env, rail: &mut ComplianceRail, index: u32) -> bool {
+    let staker = rail.backing_stakers.get(index).unwrap();
+    let amount = rail.backing_amounts.get(index).unwrap();
+    let remaining = rail.expires_at - env.ledger().timestamp();
+
+    let identity_pool: Address = env.storage().instance().get(&DataKey::IdentityPool).unwrap();
+    let backings = match IdentityPoolClient::new(env, &identity_pool)
+        .try_reallocate(&rail.agent, &staker, &amount, &remaining)
+    {
+        Ok(Ok(backings)) => backings,
+        _ => return false,
+    };
+
+    rail.backing_stakers.remove(index);
+    rail.backing_amounts.remove(index);
+    unindex_rail(env, &staker, &rail.rail_id);
+    for backing in backings.iter() {
+        match rail.backing_stakers.first_index_of(&backing.staker) {
+            Some(i) => rail.backing_amounts.set(i, rail.backing_amounts.get(i).unwrap() + backing.amount),
+            None => {
+                index_rail(env, &backing.staker, &rail.rail_id);
+                rail.backing_stakers.push_back(backing.staker);
+                rail.backing_amounts.push_back(backing.amount);
+            }
+        }
+    }
+    true
+}
+
+/// Take a staker out of up to MAX_EXIT_RAILS of the rails in its index
+fn exit_staker(env: &Env, staker: &Address, policy: ExitPolicy, reason: &str) -> StakerExit {
+    let mut outcome = StakerExit { revoked: 0, rebacked: 0, remaining: 0 };
+
+    for _ in 0..MAX_EXIT_RAILS {
+        // Every branch below drops the rail from the staker's index
+        let count = staker_rail_count(env, staker);
+        if count == 0 {
+            break;
+        }
+        let rail_id: BytesN<32> = env.storage().persistent()
+            .get(&DataKey::StakerRailAt(staker.clone(), count - 1))
+            .unwrap();
+        let mut rail: ComplianceRail = env.storage().persistent().get(&DataKey::Rail(rail_id.clone())).unwrap();
+        let index = match rail.backing_stakers.first_index_of(staker) {
+            Some(index) if rail.is_active => index,
+            _ => {
+                unindex_rail(env, staker, &rail_id);
+                continue;
+            }
+        };
+        // Expired rails only need their backing returned
+        if env.ledger().timestamp() >= rail.expires_at {
+            release_backing(env, &mut rail);
+            env.storage().persistent().set(&DataKey::Rail(rail_id), &rail);
+            continue;
+        }
+
+        if policy == ExitPolicy::Reback && reback_rail(env, &mut rail, index) {
+            outcome.rebacked += 1;
+            env.events().publish((symbol_short!("rebacked"), rail_id.clone()), staker.clone());
+        } else {
+            rail.is_active = false;
+            rail.revoked_by = Some(staker.clone());
+            rail.revoke_reason = Some(String::from_str(env, reason));
+            release_backing(env, &mut rail);
+            outcome.revoked += 1;
+            env.events().publish((symbol_short!("revoked"), rail_id.clone()), staker.clone());
+        }
+        env.storage().persistent().set(&DataKey::Rail(rail_id), &rail);
+    }
+
+    outcome.remaining = staker_rail_count(env, staker);
+    env.events().publish((symbol_short!("stk_exit"), staker.clone()), outcome.clone());
+    outcome
+}
+
+fn rail_backings(env: &Env, rail: &ComplianceRail) -> Vec<Backing> {
+    let mut backings = Vec::new(env);
+    for (staker, amount) in rail.backing_stakers.iter().zip(rail.backing_amounts.iter()) {
+        backings.push_back(Backing { staker, amount });
+    }
+    backings
+}
+
+/// Pass a rail's staker fees to its backers through the Identity Pool. If the pool
+/// refuses them (fee intake paused) the rail still stands and the fees are held here
+/// until `settle_fees` succeeds; fees none of the backers can earn any more go to the
+/// treasury
+fn settle_staker_fees(env: &Env, rail: &ComplianceRail, amount: i128) -> bool {
+    let schedule: FeeSchedule = env.storage().instance().get(&DataKey::FeeSchedule).unwrap();
+    let identity_pool: Address = env.storage().instance().get(&DataKey::IdentityPool).unwrap();
+    let key = DataKey::UnsettledFees(rail.rail_id.clone());
+    let token = token::Client::new(env, &schedule.token);
+
+    let pool = IdentityPoolClient::new(env, &identity_pool);
+    match pool.try_distribute_fees(&rail.rail_id, &amount, &rail_backings(env, rail)) {
+        Ok(Ok(())) => {
+            token.transfer(&env.current_contract_address(), &identity_pool, &amount);
+            env.storage().persistent().remove(&key);
+            true
+        }
+        Err(Ok(error)) if error == Error::from_contract_error(POOL_NO_BACKERS) => {
+            let treasury: Address = env.storage().instance().get(&DataKey::ProtocolTreasury).unwrap();
+            token.transfer(&env.current_contract_address(), &treasury, &amount);
+            env.storage().persistent().remove(&key);
+            env.events().publish((symbol_short!("fees_orph"), rail.rail_id.clone()), amount);
+            true
+        }
+        _ => {
+            env.storage().persistent().set(&key, &amount);
+            env.events().publish((symbol_short!("fees_held"), rail.rail_id.clone()), amount);
+            false
+        }
+    }
 }
 
-const PROTOCOL_FEE_BPS: i128 = 1200; // 12%
-const STAKER_FEE_BPS: i128 = 8800;   // 88%
+/// Settle a rail's query fee: the protocol share goes to the treasury and the rest
+/// to the rail's backers through the Identity Pool
+fn distribute_fees(env: &Env, rail: &ComplianceRail) {
+    let schedule: FeeSchedule = env.storage().instance().get(&DataKey::FeeSchedule).unwrap();
+    let token = token::Client::new(env, &schedule.token);
+
+    let protocol_amount = rail.query_fee * PROTOCOL_FEE_BPS / BPS_DENOMINATOR;
+    // Stakers take the remainder so no rounding dust stays behind in this contract
+    let staker_amount = rail.query_fee - protocol_amount;
+
+    if protocol_amount > 0 {
+        let treasury: Address = env.storage().instance().get(&DataKey::ProtocolTreasury).unwrap();
+        token.transfer(&env.current_contract_address(), &treasury, &protocol_amount);
+    }
+    if staker_amount > 0 {
+        settle_staker_fees(env, rail, staker_amount);
+    }
+    env.events().publish((symbol_short!("fees"), rail.rail_id.clone()), (protocol_amount, staker_amount));
+}
+
+/// Hand a rail's reserved capacity back to the Identity Pool, once, and drop it
+/// from its backers' indexes
+fn release_backing(env: &Env, rail: &mut ComplianceRail) {
+    if rail.capacity_released {
+        return;
+    }
+    rail.capacity_released = true;
+    if rail.backing_stakers.is_empty() {
+        return;
+    }
+    for staker in rail.backing_stakers.iter() {
+        unindex_rail(env, &staker, &rail.rail_id);
+    }
+
+    let identity_pool: Address = env.storage().instance().get(&DataKey::IdentityPool).unwrap();
+    IdentityPoolClient::new(env, &identity_pool).release_allocation(&rail.agent, &rail_backings(env, rail));
+}
+
+fn quote_fee(env: &Env, amount: i128) -> i128 {
+    match env.storage().instance().get::<DataKey, FeeSchedule>(&DataKey::FeeSchedule) {
+        Some(schedule) => schedule.base_fee + amount * schedule.rate_bps as i128 / BPS_DENOMINATOR,
+        None => 0,
+    }
+}
+
+/// Open a rail whose compliance proof has been checked
+fn open_rail(env: &Env, agent: Address, amount: i128, duration: u64) -> Result<BytesN<32>, RailError> {
+    // Reserve backing capacity from the Identity Pool for the whole rail
+    let identity_pool: Address = env.storage().instance().get(&DataKey::IdentityPool).unwrap();
+    let backings = match IdentityPoolClient::new(env, &identity_pool).try_allocate(&amount, &agent, &duration) {
+        Ok(Ok(backings)) => backings,
+        _ => return Err(RailError::InsufficientCapacity),
+    };
+
+    // Collect the query fee; it is settled to treasury and stakers once the rail exists
+    let query_fee = quote_fee(env, amount);
+    if query_fee > 0 {
+        let schedule: FeeSchedule = env.storage().instance().get(&DataKey::FeeSchedule).unwrap();
+        token::Client::new(env, &schedule.token)
+            .transfer(&agent, &env.current_contract_address(), &query_fee);
+    }
+
+    // Generate rail ID
+    let rail_count: u64 = env.storage().instance().get(&DataKey::RailCount).unwrap_or(0);
+    let rail_id = env.crypto().sha256(&(rail_count, agent.clone(), amount, duration).to_xdr(env)).to_bytes();
+    
+    let expires_at = env.ledger().timestamp() + duration;
+
+    let mut backing_stakers = Vec::new(env);
+    let mut backing_amounts = Vec::new(env);
+    for backing in backings.iter() {
+        backing_stakers.push_back(backing.staker);
+        backing_amounts.push_back(backing.amount);
+    }
+
+    let rail = ComplianceRail {
+        rail_id: rail_id.clone(),
+        agent: agent.clone(),
+        spending_limit: amount,
+        expires_at,
+        used_amount: 0,
+        is_active: true,
+        backing_stakers,
+        backing_amounts,
+        capacity_released: false,
+        query_fee,
+        revoked_by: None,
+        revoke_reason: None,
+    };
+
+    env.storage().persistent().set(&DataKey::Rail(rail_id.clone()), &rail);
+
+    if query_fee > 0 {
+        distribute_fees(env, &rail);
+    }
+
+    // Track rails by backing staker
+    for staker in rail.backing_stakers.iter() {
+        index_rail(env, &staker, &rail.rail_id);
+    }
+
+    // Track rails by agent
+    let mut agent_rails: Vec<BytesN<32>> = env.storage().persistent()
+        .get(&DataKey::AgentRails(agent.clone()))
+        .unwrap_or(Vec::new(env));
+    agent_rails.push_back(rail_id.clone());
+    env.storage().persistent().set(&DataKey::AgentRails(agent), &agent_rails);
+
+    // Increment rail count
+    env.storage().instance().set(&DataKey::RailCount, &(rail_count + 1));
+
+    Ok(rail_id)
+}
 
 #[contract]
 pub struct DharmaPoolContract;
@@ -31,78 +509,167 @@ pub struct DharmaPoolContract;
 #[contractimpl]
 impl DharmaPoolContract {
     /// Initialize the contract
-    pub fn initialize(env: Env, identity_pool: Address, protocol_treasury: Address) {
+    pub fn initialize(env: Env, admin: Address, identity_pool: Address, protocol_treasury: Address) {
         if env.storage().instance().has(&DataKey::IdentityPool) {
             panic!("Already initialized");
         }
+        env.storage().instance().set(&DataKey::Admin, &admin);
         env.storage().instance().set(&DataKey::IdentityPool, &identity_pool);
         env.storage().instance().set(&DataKey::ProtocolTreasury, &protocol_treasury);
         env.storage().instance().set(&DataKey::RailCount, &0u64);
     }
 
-    /// Request compliance capacity
-    pub fn request_compliance(
-        env: Env,
-        agent: Address,
-        amount: i128,
-        duration: u64,
-    ) -> Result<BytesN<32>, String> {
-        agent.require_auth();
+    /// Set the query fee charged on every rail request (admin only)
+    /// Fees must be paid in the Identity Pool's collateral token, since stakers are paid
+    /// out, compounded and redeemed in it
+    pub fn set_fee_schedule(env: Env, schedule: FeeSchedule) -> Result<(), RailError> {
+        let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+        admin.require_auth();
 
-        // TODO: In production, validate query fee payment here
-        
-        // Validate capacity (simplified - just check total)
-        // In production, this would call Identity Pool contract
-        if amount <= 0 {
-            return Err(String::from_str(&env, "Invalid amount"));
+        if schedule.base_fee < 0 || schedule.rate_bps as i128 > BPS_DENOMINATOR {
+            return Err(RailError::InvalidFeeSchedule);
+        }
+        let identity_pool: Address = env.storage().instance().get(&DataKey::IdentityPool).unwrap();
+        if IdentityPoolClient::new(&env, &identity_pool).get_collateral_token() != Some(schedule.token.clone()) {
+            return Err(RailError::InvalidFeeSchedule);
         }
+        env.storage().instance().set(&DataKey::FeeSchedule, &schedule);
+        Ok(())
+    }
 
-        // Generate rail ID
-        let rail_count: u64 = env.storage().instance().get(&DataKey::RailCount).unwrap_or(0);
-        let rail_id = env.crypto().sha256(&(rail_count, agent.clone(), amount, duration).try_into().unwrap());
-        
-        let expires_at = env.ledger().timestamp() + duration;
+    /// Register the Groth16 verifying key for rail proofs (admin only)
+    pub fn set_verifying_key(env: Env, vk: VerifyingKey) -> Result<(), RailError> {
+        let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+        admin.require_auth();
 
-        // For demo, use empty backing stakers list
-        let backing_stakers = Vec::new(&env);
+        if vk.ic.len() != PUBLIC_INPUTS + 1 {
+            return Err(RailError::InvalidVerifyingKey);
+        }
+        env.storage().instance().set(&DataKey::VerifyingKey, &vk);
+        Ok(())
+    }
 
-        let rail = ComplianceRail {
-            rail_id: rail_id.clone(),
-            agent: agent.clone(),
-            spending_limit: amount,
-            expires_at,
-            used_amount: 0,
-            is_active: true,
-            backing_stakers,
-        };
+    /// Set or clear the guardian allowed to revoke any rail (admin only)
+    pub fn set_guardian(env: Env, guardian: Option<Address>) {
+        let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+        admin.require_auth();
 
-        env.storage().persistent().set(&DataKey::Rail(rail_id.clone()), &rail);
-        
-        // Track rails by agent
-        let mut agent_rails: Vec<BytesN<32>> = env.storage().persistent()
-            .get(&DataKey::AgentRails(agent.clone()))
-            .unwrap_or(Vec::new(&env));
-        agent_rails.push_back(rail_id.clone());
-        env.storage().persistent().set(&DataKey::AgentRails(agent), &agent_rails);
+        match guardian {
+            Some(guardian) => env.storage().instance().set(&DataKey::Guardian, &guardian),
+            None => env.storage().instance().remove(&DataKey::Guardian),
+        }
+    }
+
+    /// Set the SBT contract used to let principals revoke their agents' rails (admin only)
+    pub fn set_sbt_contract(env: Env, sbt_contract: Address) {
+        let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+        admin.require_auth();
+
+        env.storage().instance().set(&DataKey::SbtContract, &sbt_contract);
+    }
+
+    /// Set, rotate or remove the attestation verifier key (admin only)
+    /// Attestations signed by a replaced key stop being accepted
+    pub fn set_attestation_key(env: Env, public_key: Option<BytesN<32>>) {
+        let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+        admin.require_auth();
+
+        match public_key.clone() {
+            Some(key) => env.storage().instance().set(&DataKey::AttestationKey, &key),
+            None => env.storage().instance().remove(&DataKey::AttestationKey),
+        }
+        env.events().publish((symbol_short!("att_key"),), public_key);
+    }
+
+    pub fn get_attestation_key(env: Env) -> Option<BytesN<32>> {
+        env.storage().instance().get(&DataKey::AttestationKey)
+    }
+
+    pub fn get_fee_schedule(env: Env) -> Option<FeeSchedule> {
+        env.storage().instance().get(&DataKey::FeeSchedule)
+    }
 
-        // Increment rail count
-        env.storage().instance().set(&DataKey::RailCount, &(rail_count + 1));
+    /// Query fee for a rail of `amount`; rails are free until a schedule is set
+    pub fn quote_fee(env: Env, amount: i128) -> i128 {
+        quote_fee(&env, amount)
+    }
+
+    /// Retry passing a rail's held staker fees to the Identity Pool (anyone may call)
+    pub fn settle_fees(env: Env, rail_id: BytesN<32>) -> Result<(), RailError> {
+        let amount: i128 = env.storage().persistent()
+            .get(&DataKey::UnsettledFees(rail_id.clone()))
+            .ok_or(RailError::NoUnsettledFees)?;
+        let rail: ComplianceRail = env.storage().persistent()
+            .get(&DataKey::Rail(rail_id))
+            .ok_or(RailError::RailNotFound)?;
 
-        Ok(rail_id)
+        if !settle_staker_fees(&env, &rail, amount) {
+            return Err(RailError::FeesRefused);
+        }
+        Ok(())
     }
 
-    /// Issue a compliance rail (after proof verification)
+    /// Staker fees of a rail still held by this contract
+    pub fn get_unsettled_fees(env: Env, rail_id: BytesN<32>) -> i128 {
+        env.storage().persistent().get(&DataKey::UnsettledFees(rail_id)).unwrap_or(0)
+    }
+
+    /// Issue a compliance rail against a Groth16 proof or a verifier attestation
+    /// of compliance for exactly this agent, amount and duration
     pub fn issue_rail(
         env: Env,
         agent: Address,
         amount: i128,
         duration: u64,
-        _proof: BytesN<64>,  // Simplified ZK proof
-    ) -> Result<BytesN<32>, String> {
-        // For demo, we skip proof verification
-        // In production, verify ZK proof here
-        
-        Self::request_compliance(env, agent, amount, duration)
+        proof: ComplianceProof,
+    ) -> Result<BytesN<32>, RailError> {
+        agent.require_auth();
+
+        if amount <= 0 {
+            return Err(RailError::InvalidAmount);
+        }
+
+        match proof {
+            ComplianceProof::Groth16(proof) => {
+                let vk: VerifyingKey = env.storage().instance()
+                    .get(&DataKey::VerifyingKey)
+                    .ok_or(RailError::VerifierNotSet)?;
+                let nonce_key = DataKey::UsedProofNonce(agent.clone(), proof.nonce);
+                if env.storage().persistent().has(&nonce_key) {
+                    return Err(RailError::NonceUsed);
+                }
+                let inputs = public_inputs(&env, &agent, amount, duration, proof.nonce);
+                if !verify_groth16(&env, &vk, &proof, &inputs) {
+                    return Err(RailError::InvalidProof);
+                }
+                env.storage().persistent().set(&nonce_key, &true);
+            }
+            ComplianceProof::Attestation(attestation) => {
+                let key: BytesN<32> = env.storage().instance()
+                    .get(&DataKey::AttestationKey)
+                    .ok_or(RailError::VerifierNotSet)?;
+                if env.ledger().timestamp() >= attestation.expires_at {
+                    return Err(RailError::AttestationExpired);
+                }
+                if env.storage().persistent().has(&DataKey::UsedNonce(attestation.nonce)) {
+                    return Err(RailError::NonceUsed);
+                }
+
+                // Traps on a bad signature
+                let payload = AttestationPayload {
+                    dharma_pool: env.current_contract_address(),
+                    agent: agent.clone(),
+                    amount,
+                    duration,
+                    expires_at: attestation.expires_at,
+                    nonce: attestation.nonce,
+                };
+                env.crypto().ed25519_verify(&key, &payload.to_xdr(&env), &attestation.signature);
+                env.storage().persistent().set(&DataKey::UsedNonce(attestation.nonce), &true);
+            }
+        }
+
+        open_rail(&env, agent, amount, duration)
     }
 
     /// Check if a rail is valid
@@ -119,73 +686,135 @@ impl DharmaPoolContract {
         env.storage().persistent().get(&DataKey::Rail(rail_id))
     }
 
-    /// Revoke a single rail
-    pub fn revoke_rail(env: Env, rail_id: BytesN<32>, caller: Address) -> Result<(), String> {
+    /// Revoke a single rail (agent, its principal, a backing staker, admin or guardian)
+    pub fn revoke_rail(env: Env, rail_id: BytesN<32>, caller: Address, reason: String) -> Result<(), RailError> {
         caller.require_auth();
 
         if let Some(mut rail) = env.storage().persistent().get::<DataKey, ComplianceRail>(&DataKey::Rail(rail_id.clone())) {
-            // Only agent or stakers can revoke
+            if !can_revoke(&env, &rail, &caller) {
+                return Err(RailError::Unauthorized);
+            }
+            if !rail.is_active {
+                return Err(RailError::RailNotActive);
+            }
+
             rail.is_active = false;
+            rail.revoked_by = Some(caller);
+            rail.revoke_reason = Some(reason);
+            release_backing(&env, &mut rail);
             env.storage().persistent().set(&DataKey::Rail(rail_id), &rail);
             Ok(())
         } else {
-            Err(String::from_str(&env, "Rail not found"))
+            Err(RailError::RailNotFound)
         }
     }
 
-    /// Revoke all rails for an agent (Kill Switch)
-    pub fn revoke_all_rails(env: Env, agent: Address) -> Result<u32, String> {
+    /// Return an expired rail's backing to the Identity Pool (permissionless)
+    pub fn release_expired_rail(env: Env, rail_id: BytesN<32>) -> Result<(), RailError> {
+        let mut rail = env.storage().persistent()
+            .get::<DataKey, ComplianceRail>(&DataKey::Rail(rail_id.clone()))
+            .ok_or(RailError::RailNotFound)?;
+
+        if env.ledger().timestamp() < rail.expires_at {
+            return Err(RailError::RailNotExpired);
+        }
+        if rail.capacity_released {
+            return Err(RailError::AlreadyReleased);
+        }
+
+        release_backing(&env, &mut rail);
+        env.storage().persistent().set(&DataKey::Rail(rail_id), &rail);
+        Ok(())
+    }
+
+    /// Revoke all rails for an agent (Kill Switch), up to MAX_EXIT_RAILS per call;
+    /// call again while `remaining` is non-zero. Handled rails leave the agent's list
+    pub fn revoke_all_rails(env: Env, agent: Address) -> Result<AgentRevocation, RailError> {
         agent.require_auth();
 
-        let agent_rails: Vec<BytesN<32>> = env.storage().persistent()
+        let mut agent_rails: Vec<BytesN<32>> = env.storage().persistent()
             .get(&DataKey::AgentRails(agent.clone()))
             .unwrap_or(Vec::new(&env));
 
-        let mut revoked_count = 0u32;
-
-        for rail_id in agent_rails.iter() {
+        let mut outcome = AgentRevocation { revoked: 0, remaining: 0 };
+        for _ in 0..MAX_EXIT_RAILS {
+            let Some(rail_id) = agent_rails.pop_back() else {
+                break;
+            };
             if let Some(mut rail) = env.storage().persistent().get::<DataKey, ComplianceRail>(&DataKey::Rail(rail_id.clone())) {
                 if rail.is_active {
                     rail.is_active = false;
+                    rail.revoked_by = Some(agent.clone());
+                    rail.revoke_reason = Some(String::from_str(&env, "Kill switch"));
+                    release_backing(&env, &mut rail);
                     env.storage().persistent().set(&DataKey::Rail(rail_id), &rail);
-                    revoked_count += 1;
+                    outcome.revoked += 1;
                 }
             }
         }
 
-        Ok(revoked_count)
+        outcome.remaining = agent_rails.len();
+        env.storage().persistent().set(&DataKey::AgentRails(agent), &agent_rails);
+        Ok(outcome)
     }
 
-    /// Revoke all rails backed by a specific staker (for staker Kill Switch)
-    pub fn revoke_staker_rails(env: Env, staker: Address) -> Result<u32, String> {
+    /// Pull a staker out of the live rails it backs (for staker Kill Switch), up to
+    /// MAX_EXIT_RAILS per call; call again while `remaining` is non-zero
+    pub fn revoke_staker_rails(env: Env, staker: Address, policy: ExitPolicy) -> Result<StakerExit, RailError> {
         staker.require_auth();
+        Ok(exit_staker(&env, &staker, policy, "Backer exit"))
+    }
 
-        // In production, iterate through all rails and check backing_stakers
-        // For demo, simplified implementation
-        
-        Ok(0)
+    /// Move a flagged staker's rails onto other stakes, revoking those that cannot be
+    /// re-backed (permissionless, up to MAX_EXIT_RAILS per call)
+    pub fn reback_flagged_staker(env: Env, staker: Address) -> Result<StakerExit, RailError> {
+        if !env.storage().persistent().has(&DataKey::FlaggedStaker(staker.clone())) {
+            return Err(RailError::StakerNotFlagged);
+        }
+        Ok(exit_staker(&env, &staker, ExitPolicy::Reback, "Backer flagged"))
     }
 
-    /// Distribute fees (simplified)
-    pub fn distribute_fees(
-        env: Env,
-        rail_id: BytesN<32>,
-        total_fee: i128,
-    ) -> Result<(), String> {
-        let rail = env.storage().persistent()
-            .get::<DataKey, ComplianceRail>(&DataKey::Rail(rail_id))
-            .ok_or(String::from_str(&env, "Rail not found"))?;
-
-        // Calculate splits
-        let protocol_amount = (total_fee * PROTOCOL_FEE_BPS) / 10000;
-        let staker_amount = (total_fee * STAKER_FEE_BPS) / 10000;
-
-        // In production:
-        // 1. Transfer protocol_amount to treasury
-        // 2. Distribute staker_amount proportionally to backing_stakers via Identity Pool
-
-        // For demo, we just log the amounts
-        Ok(())
+    /// Get a page of the unreleased rails a staker backs (at most 100 per call)
+    pub fn get_staker_rails(env: Env, staker: Address, offset: u32, limit: u32) -> Vec<BytesN<32>> {
+        let end = offset.saturating_add(limit.min(MAX_PAGE_SIZE)).min(staker_rail_count(&env, &staker));
+
+        let mut rails = Vec::new(&env);
+        for index in offset..end {
+            if let Some(rail_id) = env.storage().persistent().get::<DataKey, BytesN<32>>(&DataKey::StakerRailAt(staker.clone(), index)) {
+                rails.push_back(rail_id);
+            }
+        }
+        rails
+    }
+
+    /// Get the number of unreleased rails a staker backs
+    pub fn get_staker_rail_count(env: Env, staker: Address) -> u32 {
+        staker_rail_count(&env, &staker)
+    }
+
+    /// Flag a staker whose identity failed enforcement (called by Identity Pool)
+    /// Anyone may then move the rails it backs with `reback_flagged_staker`
+    pub fn flag_staker(env: Env, staker: Address) {
+        let identity_pool: Address = env.storage().instance().get(&DataKey::IdentityPool).unwrap();
+        identity_pool.require_auth();
+
+        let now = env.ledger().timestamp();
+        env.storage().persistent().set(&DataKey::FlaggedStaker(staker.clone()), &now);
+        env.events().publish((symbol_short!("flagged"), staker), now);
+    }
+
+    /// Clear a staker's flag once its stake is unfrozen after review (called by Identity Pool)
+    pub fn unflag_staker(env: Env, staker: Address) {
+        let identity_pool: Address = env.storage().instance().get(&DataKey::IdentityPool).unwrap();
+        identity_pool.require_auth();
+
+        env.storage().persistent().remove(&DataKey::FlaggedStaker(staker.clone()));
+        env.events().publish((symbol_short!("unflagged"), staker), env.ledger().timestamp());
+    }
+
+    /// When a staker was flagged, if ever
+    pub fn get_staker_flag(env: Env, staker: Address) -> Option<u64> {
+        env.storage().persistent().get(&DataKey::FlaggedStaker(staker))
     }
 
     /// Get all active rails for an agent
@@ -196,46 +825,205 @@ impl DharmaPoolContract {
     }
 
     /// Record rail usage (for spending limit tracking)
-    pub fn record_usage(env: Env, rail_id: BytesN<32>, amount: i128) -> Result<(), String> {
+    pub fn record_usage(env: Env, rail_id: BytesN<32>, amount: i128) -> Result<(), RailError> {
         if let Some(mut rail) = env.storage().persistent().get::<DataKey, ComplianceRail>(&DataKey::Rail(rail_id.clone())) {
             if !rail.is_active {
-                return Err(String::from_str(&env, "Rail is not active"));
+                return Err(RailError::RailNotActive);
             }
 
             if rail.used_amount + amount > rail.spending_limit {
-                return Err(String::from_str(&env, "Spending limit exceeded"));
+                return Err(RailError::SpendingLimitExceeded);
             }
 
             rail.used_amount += amount;
             env.storage().persistent().set(&DataKey::Rail(rail_id), &rail);
             Ok(())
         } else {
-            Err(String::from_str(&env, "Rail not found"))
+            Err(RailError::RailNotFound)
         }
     }
 }
 
 #[cfg(test)]
 mod test {
+    extern crate std;
+
     use super::*;
-    use soroban_sdk::{testutils::Address as _, Env};
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use ed25519_dalek::{Signer, SigningKey};
+    use soroban_sdk::{
+        testutils::{Address as _, Ledger},
+        token::{StellarAssetClient, TokenClient},
+        vec, Bytes, Env,
+    };
+
+    #[contracttype]
+    enum MockKey {
+        Backer,
+        Capacity,
+        Distributed,
+        CollateralToken,
+        RefuseFees,
+        BackersGone,
+    }
+
+    #[contracttype]
+    enum MockSbtKey {
+        Principal(Address),
+    }
+
+    const TEST_VERIFIER: [u8; 32] = [9; 32];
+    static NEXT_NONCE: AtomicU64 = AtomicU64::new(1);
+
+    fn sign(env: &Env, key: &SigningKey, payload: AttestationPayload) -> ComplianceProof {
+        let message: std::vec::Vec<u8> = payload.clone().to_xdr(env).iter().collect();
+        ComplianceProof::Attestation(Attestation {
+            expires_at: payload.expires_at,
+            nonce: payload.nonce,
+            signature: BytesN::from_array(env, &key.sign(&message).to_bytes()),
+        })
+    }
+
+    /// Register the test verifier so rails can be issued with `test_attestation`
+    fn trust_test_verifier(env: &Env, client: &DharmaPoolContractClient) {
+        let key = SigningKey::from_bytes(&TEST_VERIFIER);
+        client.set_attestation_key(&Some(BytesN::from_array(env, &key.verifying_key().to_bytes())));
+    }
+
+    /// A fresh attestation from the test verifier for exactly this rail
+    fn test_attestation(env: &Env, client: &DharmaPoolContractClient, agent: &Address, amount: i128, duration: u64) -> ComplianceProof {
+        sign(env, &SigningKey::from_bytes(&TEST_VERIFIER), AttestationPayload {
+            dharma_pool: client.address.clone(),
+            agent: agent.clone(),
+            amount,
+            duration,
+            expires_at: env.ledger().timestamp() + 600,
+            nonce: NEXT_NONCE.fetch_add(1, Ordering::Relaxed),
+        })
+    }
+
+    /// Stands in for the SBT contract's agent registry
+    #[contract]
+    pub struct MockSbt;
+
+    #[contractimpl]
+    impl MockSbt {
+        pub fn set_principal(env: Env, agent: Address, principal: Address) {
+            env.storage().persistent().set(&MockSbtKey::Principal(agent), &principal);
+        }
+
+        pub fn get_principal(env: Env, agent: Address) -> Option<Address> {
+            env.storage().persistent().get(&MockSbtKey::Principal(agent))
+        }
+    }
+
+    /// Stands in for the Identity Pool, backing every rail from a single staker
+    #[contract]
+    pub struct MockIdentityPool;
+
+    #[contractimpl]
+    impl MockIdentityPool {
+        pub fn set_capacity(env: Env, backer: Address, capacity: i128) {
+            env.storage().instance().set(&MockKey::Backer, &backer);
+            env.storage().instance().set(&MockKey::Capacity, &capacity);
+        }
+
+        pub fn get_capacity(env: Env) -> i128 {
+            env.storage().instance().get(&MockKey::Capacity).unwrap_or(0)
+        }
+
+        pub fn allocate(env: Env, amount: i128, _agent: Address, _duration: u64) -> Result<Vec<Backing>, Error> {
+            let capacity = Self::get_capacity(env.clone());
+            if amount > capacity {
+                return Err(Error::from_contract_error(1));
+            }
+            env.storage().instance().set(&MockKey::Capacity, &(capacity - amount));
+            let backer: Address = env.storage().instance().get(&MockKey::Backer).unwrap();
+            Ok(vec![&env, Backing { staker: backer, amount }])
+        }
+
+        pub fn reallocate(
+            env: Env,
+            _agent: Address,
+            _from: Address,
+            amount: i128,
+            _duration: u64,
+        ) -> Result<Vec<Backing>, Error> {
+            Self::allocate(env, amount, _agent, _duration)
+        }
+
+        pub fn distribute_fees(env: Env, _rail_id: BytesN<32>, amount: i128, _backings: Vec<Backing>) -> Result<(), Error> {
+            if env.storage().instance().has(&MockKey::RefuseFees) {
+                return Err(Error::from_contract_error(36));
+            }
+            if env.storage().instance().has(&MockKey::BackersGone) {
+                return Err(Error::from_contract_error(POOL_NO_BACKERS));
+            }
+            let distributed = Self::get_distributed(env.clone());
+            env.storage().instance().set(&MockKey::Distributed, &(distributed + amount));
+            Ok(())
+        }
+
+        pub fn get_distributed(env: Env) -> i128 {
+            env.storage().instance().get(&MockKey::Distributed).unwrap_or(0)
+        }
+
+        /// Refuse fee credits, as the Identity Pool does while fee intake is paused
+        pub fn set_refuse_fees(env: Env, refuse: bool) {
+            if refuse {
+                env.storage().instance().set(&MockKey::RefuseFees, &true);
+            } else {
+                env.storage().instance().remove(&MockKey::RefuseFees);
+            }
+        }
+
+        /// Report that none of a rail's backers can still earn, as the Identity Pool does
+        /// once they have all left
+        pub fn set_backers_gone(env: Env, gone: bool) {
+            if gone {
+                env.storage().instance().set(&MockKey::BackersGone, &true);
+            } else {
+                env.storage().instance().remove(&MockKey::BackersGone);
+            }
+        }
+
+        pub fn set_collateral_token(env: Env, token: Address) {
+            env.storage().instance().set(&MockKey::CollateralToken, &token);
+        }
+
+        pub fn get_collateral_token(env: Env) -> Option<Address> {
+            env.storage().instance().get(&MockKey::CollateralToken)
+        }
+
+        pub fn release_allocation(env: Env, _agent: Address, backings: Vec<Backing>) -> Result<(), Error> {
+            let mut capacity = Self::get_capacity(env.clone());
+            for backing in backings.iter() {
+                capacity += backing.amount;
+            }
+            env.storage().instance().set(&MockKey::Capacity, &capacity);
+            Ok(())
+        }
+    }
 
     #[test]
     fn test_request_and_check_rail() {
         let env = Env::default();
-        let contract_id = env.register_contract(None, DharmaPoolContract);
+        let contract_id = env.register(DharmaPoolContract, ());
         let client = DharmaPoolContractClient::new(&env, &contract_id);
 
-        let identity_pool = Address::generate(&env);
+        let admin = Address::generate(&env);
+        let identity_pool = env.register(MockIdentityPool, ());
         let treasury = Address::generate(&env);
         let agent = Address::generate(&env);
 
-        client.initialize(&identity_pool, &treasury);
+        client.initialize(&admin, &identity_pool, &treasury);
+        MockIdentityPoolClient::new(&env, &identity_pool).set_capacity(&Address::generate(&env), &100_000_000);
 
         env.mock_all_auths();
+        trust_test_verifier(&env, &client);
         
         // Request compliance
-        let rail_id = client.request_compliance(&agent, &10_000_000, &3600).unwrap();
+        let rail_id = client.issue_rail(&agent, &10_000_000, &3600, &test_attestation(&env, &client, &agent, 10_000_000, 3600));
 
         // Check validity
         assert!(client.check_rail_validity(&rail_id));
@@ -250,76 +1038,533 @@ mod test {
     #[test]
     fn test_revoke_rail() {
         let env = Env::default();
-        let contract_id = env.register_contract(None, DharmaPoolContract);
+        let contract_id = env.register(DharmaPoolContract, ());
         let client = DharmaPoolContractClient::new(&env, &contract_id);
 
-        let identity_pool = Address::generate(&env);
+        let admin = Address::generate(&env);
+        let identity_pool = env.register(MockIdentityPool, ());
         let treasury = Address::generate(&env);
         let agent = Address::generate(&env);
 
-        client.initialize(&identity_pool, &treasury);
+        client.initialize(&admin, &identity_pool, &treasury);
+        MockIdentityPoolClient::new(&env, &identity_pool).set_capacity(&Address::generate(&env), &100_000_000);
 
         env.mock_all_auths();
+        trust_test_verifier(&env, &client);
         
-        let rail_id = client.request_compliance(&agent, &10_000_000, &3600).unwrap();
+        let rail_id = client.issue_rail(&agent, &10_000_000, &3600, &test_attestation(&env, &client, &agent, 10_000_000, 3600));
         assert!(client.check_rail_validity(&rail_id));
 
         // Revoke
-        client.revoke_rail(&rail_id, &agent).unwrap();
+        client.revoke_rail(&rail_id, &agent, &String::from_str(&env, "Done"));
         assert!(!client.check_rail_validity(&rail_id));
     }
 
     #[test]
     fn test_kill_switch() {
         let env = Env::default();
-        let contract_id = env.register_contract(None, DharmaPoolContract);
+        let contract_id = env.register(DharmaPoolContract, ());
         let client = DharmaPoolContractClient::new(&env, &contract_id);
 
-        let identity_pool = Address::generate(&env);
+        let admin = Address::generate(&env);
+        let identity_pool = env.register(MockIdentityPool, ());
         let treasury = Address::generate(&env);
         let agent = Address::generate(&env);
 
-        client.initialize(&identity_pool, &treasury);
+        client.initialize(&admin, &identity_pool, &treasury);
+        MockIdentityPoolClient::new(&env, &identity_pool).set_capacity(&Address::generate(&env), &100_000_000);
 
         env.mock_all_auths();
+        trust_test_verifier(&env, &client);
         
         // Create multiple rails
-        let rail1 = client.request_compliance(&agent, &10_000_000, &3600).unwrap();
-        let rail2 = client.request_compliance(&agent, &20_000_000, &7200).unwrap();
+        let rail1 = client.issue_rail(&agent, &10_000_000, &3600, &test_attestation(&env, &client, &agent, 10_000_000, 3600));
+        let rail2 = client.issue_rail(&agent, &20_000_000, &7200, &test_attestation(&env, &client, &agent, 20_000_000, 7200));
 
         assert!(client.check_rail_validity(&rail1));
         assert!(client.check_rail_validity(&rail2));
 
         // Kill switch
-        let revoked = client.revoke_all_rails(&agent).unwrap();
-        assert_eq!(revoked, 2);
+        let outcome = client.revoke_all_rails(&agent);
+        assert_eq!(outcome, AgentRevocation { revoked: 2, remaining: 0 });
 
         assert!(!client.check_rail_validity(&rail1));
         assert!(!client.check_rail_validity(&rail2));
+        assert_eq!(client.get_agent_rails(&agent).len(), 0);
+
+        // Large rail lists are revoked in bounded batches
+        for _ in 0..MAX_EXIT_RAILS + 2 {
+            client.issue_rail(&agent, &1_000_000, &3600, &test_attestation(&env, &client, &agent, 1_000_000, 3600));
+        }
+        let outcome = client.revoke_all_rails(&agent);
+        assert_eq!(outcome, AgentRevocation { revoked: MAX_EXIT_RAILS, remaining: 2 });
+        let outcome = client.revoke_all_rails(&agent);
+        assert_eq!(outcome, AgentRevocation { revoked: 2, remaining: 0 });
     }
 
     #[test]
     fn test_spending_limit() {
         let env = Env::default();
-        let contract_id = env.register_contract(None, DharmaPoolContract);
+        let contract_id = env.register(DharmaPoolContract, ());
         let client = DharmaPoolContractClient::new(&env, &contract_id);
 
-        let identity_pool = Address::generate(&env);
+        let admin = Address::generate(&env);
+        let identity_pool = env.register(MockIdentityPool, ());
         let treasury = Address::generate(&env);
         let agent = Address::generate(&env);
 
-        client.initialize(&identity_pool, &treasury);
+        client.initialize(&admin, &identity_pool, &treasury);
+        MockIdentityPoolClient::new(&env, &identity_pool).set_capacity(&Address::generate(&env), &100_000_000);
 
         env.mock_all_auths();
+        trust_test_verifier(&env, &client);
         
-        let rail_id = client.request_compliance(&agent, &10_000_000, &3600).unwrap();
+        let rail_id = client.issue_rail(&agent, &10_000_000, &3600, &test_attestation(&env, &client, &agent, 10_000_000, 3600));
 
         // Record usage
-        client.record_usage(&rail_id, &5_000_000).unwrap();
-        client.record_usage(&rail_id, &4_000_000).unwrap();
+        client.record_usage(&rail_id, &5_000_000);
+        client.record_usage(&rail_id, &4_000_000);
 
         // Should fail - exceeds limit
-        let result = client.record_usage(&rail_id, &2_000_000);
+        let result = client.try_record_usage(&rail_id, &2_000_000);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_flag_staker() {
+        let env = Env::default();
+        let contract_id = env.register(DharmaPoolContract, ());
+        let client = DharmaPoolContractClient::new(&env, &contract_id);
+
+        let admin = Address::generate(&env);
+        let identity_pool = env.register(MockIdentityPool, ());
+        let pool = MockIdentityPoolClient::new(&env, &identity_pool);
+        let treasury = Address::generate(&env);
+        let agent = Address::generate(&env);
+        let staker = Address::generate(&env);
+        let replacement = Address::generate(&env);
+
+        client.initialize(&admin, &identity_pool, &treasury);
+        pool.set_capacity(&staker, &100_000_000);
+
+        env.mock_all_auths();
+        trust_test_verifier(&env, &client);
+        let rail_id = client.issue_rail(&agent, &5_000_000, &3600, &test_attestation(&env, &client, &agent, 5_000_000, 3600));
+
+        // Only a flagged staker's rails can be moved without its consent
+        assert_eq!(client.try_reback_flagged_staker(&staker), Err(Ok(RailError::StakerNotFlagged)));
+        assert_eq!(client.get_staker_flag(&staker), None);
+        client.flag_staker(&staker);
+        assert_eq!(client.get_staker_flag(&staker), Some(env.ledger().timestamp()));
+
+        pool.set_capacity(&replacement, &100_000_000);
+        env.set_auths(&[]);
+        let outcome = client.reback_flagged_staker(&staker);
+        assert_eq!(outcome, StakerExit { revoked: 0, rebacked: 1, remaining: 0 });
+        assert!(client.check_rail_validity(&rail_id));
+        assert_eq!(client.get_rail(&rail_id).unwrap().backing_stakers, vec![&env, replacement.clone()]);
+        assert_eq!(client.get_staker_rail_count(&staker), 0);
+
+        // A stake unfrozen after review is no longer fair game
+        env.mock_all_auths();
+        client.unflag_staker(&staker);
+        assert_eq!(client.get_staker_flag(&staker), None);
+        assert_eq!(client.try_reback_flagged_staker(&staker), Err(Ok(RailError::StakerNotFlagged)));
+    }
+
+    #[test]
+    fn test_query_fee() {
+        let env = Env::default();
+        let contract_id = env.register(DharmaPoolContract, ());
+        let client = DharmaPoolContractClient::new(&env, &contract_id);
+
+        let admin = Address::generate(&env);
+        let identity_pool = env.register(MockIdentityPool, ());
+        let treasury = Address::generate(&env);
+        let agent = Address::generate(&env);
+
+        client.initialize(&admin, &identity_pool, &treasury);
+        MockIdentityPoolClient::new(&env, &identity_pool).set_capacity(&Address::generate(&env), &100_000_000);
+
+        env.mock_all_auths();
+        trust_test_verifier(&env, &client);
+        let token_address = env.register_stellar_asset_contract_v2(admin.clone()).address();
+        let token = TokenClient::new(&env, &token_address);
+        StellarAssetClient::new(&env, &token_address).mint(&agent, &1_000_000);
+
+        // Fees must be paid in the collateral token stakers are paid out in
+        let other_token = env.register_stellar_asset_contract_v2(admin.clone()).address();
+        let schedule = FeeSchedule { token: token_address.clone(), base_fee: 10_000, rate_bps: 50 };
+        assert_eq!(client.try_set_fee_schedule(&schedule), Err(Ok(RailError::InvalidFeeSchedule)));
+        MockIdentityPoolClient::new(&env, &identity_pool).set_collateral_token(&token_address);
+        let mismatched = FeeSchedule { token: other_token, base_fee: 10_000, rate_bps: 50 };
+        assert_eq!(client.try_set_fee_schedule(&mismatched), Err(Ok(RailError::InvalidFeeSchedule)));
+
+        // 1 USDC flat plus 0.5% of the requested amount
+        client.set_fee_schedule(&FeeSchedule { token: token_address.clone(), base_fee: 10_000, rate_bps: 50 });
+        assert_eq!(client.quote_fee(&10_000_000), 60_000);
+
+        let rail_id = client.issue_rail(&agent, &10_000_000, &3600, &test_attestation(&env, &client, &agent, 10_000_000, 3600));
+        assert_eq!(client.get_rail(&rail_id).unwrap().query_fee, 60_000);
+        assert_eq!(token.balance(&agent), 940_000);
+
+        // 12% to the treasury, the rest to the rail's backers
+        assert_eq!(token.balance(&treasury), 7_200);
+        assert_eq!(token.balance(&identity_pool), 52_800);
+        assert_eq!(token.balance(&contract_id), 0);
+        assert_eq!(MockIdentityPoolClient::new(&env, &identity_pool).get_distributed(), 52_800);
+
+        // Dust from the protocol share rounds toward the stakers
+        client.set_fee_schedule(&FeeSchedule { token: token_address.clone(), base_fee: 99, rate_bps: 0 });
+        client.issue_rail(&agent, &10_000_000, &3600, &test_attestation(&env, &client, &agent, 10_000_000, 3600));
+        assert_eq!(token.balance(&treasury), 7_211);
+        assert_eq!(token.balance(&identity_pool), 52_888);
+        assert_eq!(token.balance(&contract_id), 0);
+
+        // While the Identity Pool refuses fees, rails still open and the staker share is held
+        let pool = MockIdentityPoolClient::new(&env, &identity_pool);
+        pool.set_refuse_fees(&true);
+        let held = client.issue_rail(&agent, &10_000_000, &3600, &test_attestation(&env, &client, &agent, 10_000_000, 3600));
+        assert_eq!(token.balance(&treasury), 7_222);
+        assert_eq!(client.get_unsettled_fees(&held), 88);
+        assert_eq!(token.balance(&contract_id), 88);
+        assert_eq!(client.try_settle_fees(&held), Err(Ok(RailError::FeesRefused)));
+
+        pool.set_refuse_fees(&false);
+        client.settle_fees(&held);
+        assert_eq!(client.get_unsettled_fees(&held), 0);
+        assert_eq!(token.balance(&identity_pool), 52_976);
+        assert_eq!(token.balance(&contract_id), 0);
+        assert_eq!(client.try_settle_fees(&held), Err(Ok(RailError::NoUnsettledFees)));
+
+        // Held fees whose backers have all left since go to the treasury instead
+        pool.set_refuse_fees(&true);
+        let orphaned = client.issue_rail(&agent, &10_000_000, &3600, &test_attestation(&env, &client, &agent, 10_000_000, 3600));
+        pool.set_refuse_fees(&false);
+        pool.set_backers_gone(&true);
+        client.settle_fees(&orphaned);
+        assert_eq!(client.get_unsettled_fees(&orphaned), 0);
+        assert_eq!(token.balance(&treasury), 7_233 + 88);
+        assert_eq!(token.balance(&identity_pool), 52_976);
+        assert_eq!(token.balance(&contract_id), 0);
+        pool.set_backers_gone(&false);
+
+        // An agent that cannot pay gets no rail
+        let unfunded = Address::generate(&env);
+        assert!(client.try_issue_rail(&unfunded, &10_000_000, &3600, &test_attestation(&env, &client, &unfunded, 10_000_000, 3600)).is_err());
+    }
+
+    #[test]
+    fn test_rail_backing() {
+        let env = Env::default();
+        let contract_id = env.register(DharmaPoolContract, ());
+        let client = DharmaPoolContractClient::new(&env, &contract_id);
+
+        let admin = Address::generate(&env);
+        let identity_pool = env.register(MockIdentityPool, ());
+        let pool = MockIdentityPoolClient::new(&env, &identity_pool);
+        let treasury = Address::generate(&env);
+        let agent = Address::generate(&env);
+        let staker = Address::generate(&env);
+
+        client.initialize(&admin, &identity_pool, &treasury);
+        pool.set_capacity(&staker, &15_000_000);
+
+        env.mock_all_auths();
+        trust_test_verifier(&env, &client);
+
+        // Rails are backed by the stakes the Identity Pool picked
+        let expiring = client.issue_rail(&agent, &10_000_000, &3600, &test_attestation(&env, &client, &agent, 10_000_000, 3600));
+        let rail = client.get_rail(&expiring).unwrap();
+        assert_eq!(rail.backing_stakers, vec![&env, staker.clone()]);
+        assert_eq!(rail.backing_amounts, vec![&env, 10_000_000]);
+        assert_eq!(pool.get_capacity(), 5_000_000);
+
+        // Without capacity there is no rail
+        assert_eq!(
+            client.try_issue_rail(&agent, &10_000_000, &3600, &test_attestation(&env, &client, &agent, 10_000_000, 3600)),
+            Err(Ok(RailError::InsufficientCapacity))
+        );
+
+        // Revoking releases the backing
+        let revoked = client.issue_rail(&agent, &5_000_000, &7200, &test_attestation(&env, &client, &agent, 5_000_000, 7200));
+        assert_eq!(pool.get_capacity(), 0);
+        client.revoke_rail(&revoked, &agent, &String::from_str(&env, "Done"));
+        assert_eq!(pool.get_capacity(), 5_000_000);
+
+        // So does expiry, once
+        assert!(client.try_release_expired_rail(&expiring).is_err());
+        env.ledger().set_timestamp(env.ledger().timestamp() + 3600);
+        client.release_expired_rail(&expiring);
+        assert_eq!(pool.get_capacity(), 15_000_000);
+        assert!(client.try_release_expired_rail(&expiring).is_err());
+    }
+
+    #[test]
+    fn test_issue_rail_groth16() {
+        let env = Env::default();
+        let contract_id = env.register(DharmaPoolContract, ());
+        let client = DharmaPoolContractClient::new(&env, &contract_id);
+
+        let admin = Address::generate(&env);
+        let identity_pool = env.register(MockIdentityPool, ());
+        let treasury = Address::generate(&env);
+        let agent = Address::generate(&env);
+
+        client.initialize(&admin, &identity_pool, &treasury);
+        MockIdentityPoolClient::new(&env, &identity_pool).set_capacity(&Address::generate(&env), &100_000_000);
+
+        env.mock_all_auths();
+
+        // Build a key and proof from known discrete logs over two fixed points,
+        // so that A = alpha * beta + vk_x + c (as scalars of P, with B = gamma = delta = Q)
+        let bls = env.crypto().bls12_381();
+        let dst = Bytes::from_slice(&env, b"DHARMA-POOL-TEST");
+        let p = bls.hash_to_g1(&Bytes::from_slice(&env, b"P"), &dst);
+        let q = bls.hash_to_g2(&Bytes::from_slice(&env, b"Q"), &dst);
+        let scalar = |n: u32| Fr::from_u256(U256::from_u32(&env, n));
+        let (alpha, beta, c) = (scalar(2), scalar(3), scalar(5));
+        let ic = [scalar(7), scalar(11), scalar(13), scalar(17), scalar(19)];
+
+        let mut ic_points = Vec::new(&env);
+        for k in ic.iter() {
+            ic_points.push_back(bls.g1_mul(&p, k).to_bytes());
+        }
+        let vk = VerifyingKey {
+            alpha: bls.g1_mul(&p, &alpha).to_bytes(),
+            beta: bls.g2_mul(&q, &beta).to_bytes(),
+            gamma: q.to_bytes(),
+            delta: q.to_bytes(),
+            ic: ic_points,
+        };
+
+        let prove = |amount: i128, duration: u64, nonce: u64| {
+            let mut x = bls.fr_add(&bls.fr_mul(&alpha, &beta), &ic[0]);
+            for (i, input) in public_inputs(&env, &agent, amount, duration, nonce).iter().enumerate() {
+                x = bls.fr_add(&x, &bls.fr_mul(&input, &ic[i + 1]));
+            }
+            x = bls.fr_add(&x, &c);
+            ComplianceProof::Groth16(Groth16Proof {
+                a: bls.g1_mul(&p, &x).to_bytes(),
+                b: q.to_bytes(),
+                c: bls.g1_mul(&p, &c).to_bytes(),
+                nonce,
+            })
+        };
+        let proof = prove(10_000_000, 3600, 1);
+
+        // No key, no rails
+        assert_eq!(
+            client.try_issue_rail(&agent, &10_000_000, &3600, &proof),
+            Err(Ok(RailError::VerifierNotSet))
+        );
+        client.set_verifying_key(&vk);
+
+        // The proof only holds for the agent, amount and duration it was made for
+        assert_eq!(
+            client.try_issue_rail(&agent, &20_000_000, &3600, &proof),
+            Err(Ok(RailError::InvalidProof))
+        );
+        assert_eq!(
+            client.try_issue_rail(&agent, &10_000_000, &7200, &proof),
+            Err(Ok(RailError::InvalidProof))
+        );
+        let rail_id = client.issue_rail(&agent, &10_000_000, &3600, &proof);
+        assert!(client.check_rail_validity(&rail_id));
+
+        // Each proof opens one rail
+        assert_eq!(
+            client.try_issue_rail(&agent, &10_000_000, &3600, &proof),
+            Err(Ok(RailError::NonceUsed))
+        );
+        client.issue_rail(&agent, &10_000_000, &3600, &prove(10_000_000, 3600, 2));
+    }
+
+    #[test]
+    fn test_issue_rail_attestation() {
+        let env = Env::default();
+        let contract_id = env.register(DharmaPoolContract, ());
+        let client = DharmaPoolContractClient::new(&env, &contract_id);
+
+        let admin = Address::generate(&env);
+        let identity_pool = env.register(MockIdentityPool, ());
+        let treasury = Address::generate(&env);
+        let agent = Address::generate(&env);
+
+        client.initialize(&admin, &identity_pool, &treasury);
+        MockIdentityPoolClient::new(&env, &identity_pool).set_capacity(&Address::generate(&env), &100_000_000);
+
+        env.mock_all_auths();
+        let verifier = SigningKey::from_bytes(&[7; 32]);
+        let rotated = SigningKey::from_bytes(&[8; 32]);
+        let attest = |key: &SigningKey, amount: i128, expires_at: u64, nonce: u64| {
+            sign(&env, key, AttestationPayload {
+                dharma_pool: contract_id.clone(),
+                agent: agent.clone(),
+                amount,
+                duration: 3600,
+                expires_at,
+                nonce,
+            })
+        };
+        let expires_at = env.ledger().timestamp() + 600;
+
+        assert_eq!(
+            client.try_issue_rail(&agent, &10_000_000, &3600, &attest(&verifier, 10_000_000, expires_at, 1)),
+            Err(Ok(RailError::VerifierNotSet))
+        );
+        client.set_attestation_key(&Some(BytesN::from_array(&env, &verifier.verifying_key().to_bytes())));
+
+        let rail_id = client.issue_rail(&agent, &10_000_000, &3600, &attest(&verifier, 10_000_000, expires_at, 1));
+        assert!(client.check_rail_validity(&rail_id));
+
+        // Each nonce is good for one rail
+        assert_eq!(
+            client.try_issue_rail(&agent, &10_000_000, &3600, &attest(&verifier, 10_000_000, expires_at, 1)),
+            Err(Ok(RailError::NonceUsed))
+        );
+
+        // Attestations cover the amount and expire
+        assert!(client.try_issue_rail(&agent, &20_000_000, &3600, &attest(&verifier, 10_000_000, expires_at, 2)).is_err());
+        env.ledger().set_timestamp(expires_at);
+        assert_eq!(
+            client.try_issue_rail(&agent, &10_000_000, &3600, &attest(&verifier, 10_000_000, expires_at, 3)),
+            Err(Ok(RailError::AttestationExpired))
+        );
+
+        // After rotation only the new key is accepted
+        client.set_attestation_key(&Some(BytesN::from_array(&env, &rotated.verifying_key().to_bytes())));
+        let expires_at = expires_at + 600;
+        assert!(client.try_issue_rail(&agent, &10_000_000, &3600, &attest(&verifier, 10_000_000, expires_at, 4)).is_err());
+        client.issue_rail(&agent, &10_000_000, &3600, &attest(&rotated, 10_000_000, expires_at, 5));
+    }
+
+    #[test]
+    fn test_revoke_permissions() {
+        let env = Env::default();
+        let contract_id = env.register(DharmaPoolContract, ());
+        let client = DharmaPoolContractClient::new(&env, &contract_id);
+        let sbt_contract = env.register(MockSbt, ());
+
+        let admin = Address::generate(&env);
+        let identity_pool = env.register(MockIdentityPool, ());
+        let treasury = Address::generate(&env);
+        let agent = Address::generate(&env);
+        let principal = Address::generate(&env);
+        let staker = Address::generate(&env);
+        let guardian = Address::generate(&env);
+        let stranger = Address::generate(&env);
+
+        client.initialize(&admin, &identity_pool, &treasury);
+        MockIdentityPoolClient::new(&env, &identity_pool).set_capacity(&staker, &100_000_000);
+        MockSbtClient::new(&env, &sbt_contract).set_principal(&agent, &principal);
+
+        env.mock_all_auths();
+        trust_test_verifier(&env, &client);
+        client.set_sbt_contract(&sbt_contract);
+        client.set_guardian(&Some(guardian.clone()));
+        let reason = String::from_str(&env, "Suspicious activity");
+
+        // Unrelated addresses cannot revoke
+        let rail_id = client.issue_rail(&agent, &1_000_000, &3600, &test_attestation(&env, &client, &agent, 1_000_000, 3600));
+        assert!(client.try_revoke_rail(&rail_id, &stranger, &reason).is_err());
+        assert!(client.check_rail_validity(&rail_id));
+
+        // The agent's principal, backing stakers, guardian and admin can
+        for revoker in [&principal, &staker, &guardian, &admin] {
+            let rail_id = client.issue_rail(&agent, &1_000_000, &3600, &test_attestation(&env, &client, &agent, 1_000_000, 3600));
+            client.revoke_rail(&rail_id, revoker, &reason);
+            let rail = client.get_rail(&rail_id).unwrap();
+            assert!(!rail.is_active);
+            assert_eq!(rail.revoked_by, Some(revoker.clone()));
+            assert_eq!(rail.revoke_reason, Some(reason.clone()));
+        }
+    }
+
+    #[test]
+    fn test_revoke_staker_rails() {
+        let env = Env::default();
+        let contract_id = env.register(DharmaPoolContract, ());
+        let client = DharmaPoolContractClient::new(&env, &contract_id);
+
+        let admin = Address::generate(&env);
+        let identity_pool = env.register(MockIdentityPool, ());
+        let pool = MockIdentityPoolClient::new(&env, &identity_pool);
+        let treasury = Address::generate(&env);
+        let agent = Address::generate(&env);
+        let leaving = Address::generate(&env);
+        let replacement = Address::generate(&env);
+
+        client.initialize(&admin, &identity_pool, &treasury);
+        pool.set_capacity(&leaving, &100_000_000);
+
+        env.mock_all_auths();
+        trust_test_verifier(&env, &client);
+        let small = client.issue_rail(&agent, &5_000_000, &3600, &test_attestation(&env, &client, &agent, 5_000_000, 3600));
+        let large = client.issue_rail(&agent, &8_000_000, &3600, &test_attestation(&env, &client, &agent, 8_000_000, 3600));
+        assert_eq!(client.get_staker_rails(&leaving, &0, &10), vec![&env, small.clone(), large.clone()]);
+
+        // Only the small rail fits on the remaining capacity; the large one is revoked
+        pool.set_capacity(&replacement, &6_000_000);
+        let outcome = client.revoke_staker_rails(&leaving, &ExitPolicy::Reback);
+        assert_eq!(outcome, StakerExit { revoked: 1, rebacked: 1, remaining: 0 });
+
+        let rail = client.get_rail(&small).unwrap();
+        assert!(rail.is_active);
+        assert_eq!(rail.backing_stakers, vec![&env, replacement.clone()]);
+        assert_eq!(rail.backing_amounts, vec![&env, 5_000_000]);
+        assert!(!client.check_rail_validity(&large));
+        assert_eq!(client.get_rail(&large).unwrap().revoked_by, Some(leaving.clone()));
+        assert_eq!(client.get_staker_rail_count(&leaving), 0);
+        assert_eq!(client.get_staker_rails(&replacement, &0, &10), vec![&env, small.clone()]);
+
+        // Revoke policy kills the rail outright
+        let outcome = client.revoke_staker_rails(&replacement, &ExitPolicy::Revoke);
+        assert_eq!(outcome, StakerExit { revoked: 1, rebacked: 0, remaining: 0 });
+        assert!(!client.check_rail_validity(&small));
+        assert_eq!(client.get_staker_rail_count(&replacement), 0);
+    }
+
+    #[test]
+    fn test_staker_rail_index() {
+        let env = Env::default();
+        let contract_id = env.register(DharmaPoolContract, ());
+        let client = DharmaPoolContractClient::new(&env, &contract_id);
+
+        let admin = Address::generate(&env);
+        let identity_pool = env.register(MockIdentityPool, ());
+        let treasury = Address::generate(&env);
+        let agent = Address::generate(&env);
+        let staker = Address::generate(&env);
+
+        client.initialize(&admin, &identity_pool, &treasury);
+        MockIdentityPoolClient::new(&env, &identity_pool).set_capacity(&staker, &1_000_000_000);
+
+        env.mock_all_auths();
+        trust_test_verifier(&env, &client);
+        let mut rails = Vec::new(&env);
+        for _ in 0..MAX_EXIT_RAILS + 3 {
+            rails.push_back(client.issue_rail(&agent, &1_000_000, &3600, &test_attestation(&env, &client, &agent, 1_000_000, 3600)));
+        }
+        assert_eq!(client.get_staker_rail_count(&staker), MAX_EXIT_RAILS + 3);
+
+        // Revoked and released rails leave the index; the last entry fills the gap
+        client.revoke_rail(&rails.get(0).unwrap(), &agent, &String::from_str(&env, "Done"));
+        assert_eq!(client.get_staker_rail_count(&staker), MAX_EXIT_RAILS + 2);
+        assert_eq!(client.get_staker_rails(&staker, &0, &1), vec![&env, rails.last().unwrap()]);
+
+        // Exits are handled in bounded batches
+        let outcome = client.revoke_staker_rails(&staker, &ExitPolicy::Revoke);
+        assert_eq!(outcome, StakerExit { revoked: MAX_EXIT_RAILS, rebacked: 0, remaining: 2 });
+        let outcome = client.revoke_staker_rails(&staker, &ExitPolicy::Revoke);
+        assert_eq!(outcome, StakerExit { revoked: 2, rebacked: 0, remaining: 0 });
+        assert_eq!(client.get_staker_rails(&staker, &0, &100).len(), 0);
+
+        // Expired rails leave the index when released
+        let expiring = client.issue_rail(&agent, &1_000_000, &3600, &test_attestation(&env, &client, &agent, 1_000_000, 3600));
+        assert_eq!(client.get_staker_rail_count(&staker), 1);
+        env.ledger().set_timestamp(env.ledger().timestamp() + 3600);
+        client.release_expired_rail(&expiring);
+        assert_eq!(client.get_staker_rail_count(&staker), 0);
+    }
 }
@@ -1,5 +1,72 @@
 #![no_std]
-use soroban_sdk::{contract, contractimpl, contracttype, Address, Env, String, Vec};
+use soroban_sdk::{
+    contract, contractclient, contracterror, contractimpl, contracttype, symbol_short, token, Address, BytesN, Env,
+    String, vec, Vec,
+};
+
+/// The subset of the SBT contract the pool relies on
+#[contractclient(name = "SbtClient")]
+pub trait SbtInterface {
+    fn verify_sbt(env: Env, owner: Address) -> bool;
+    fn get_assurance_level(env: Env, owner: Address) -> u32;
+    fn get_principal(env: Env, agent: Address) -> Option<Address>;
+}
+
+/// The subset of the Dharma Pool the identity pool calls back into
+#[contractclient(name = "DharmaClient")]
+pub trait DharmaInterface {
+    fn flag_staker(env: Env, staker: Address);
+    fn unflag_staker(env: Env, staker: Address);
+}
+
+#[contracterror]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum PoolError {
+    InvalidSpendingLimit = 1,
+    AlreadyStaked = 2,
+    StakeNotFound = 3,
+    InvalidAmount = 4,
+    StakeNotActive = 5,
+    InsufficientCapacity = 6,
+    InvalidTimeBound = 7,
+    BelowAllocated = 8,
+    TimeBoundNotExtended = 9,
+    UnstakePending = 10,
+    NotUnbonding = 11,
+    CapacityStillAllocated = 12,
+    CollateralTokenNotSet = 13,
+    Unauthorized = 14,
+    CollateralTokenAlreadySet = 15,
+    AgentNotAccepted = 16,
+    AgentTierTooLow = 17,
+    RailDurationTooLong = 18,
+    ExposureLimitExceeded = 19,
+    PolicyListTooLong = 20,
+    InvalidExposureLimit = 21,
+    InvalidOperator = 22,
+    InvalidConfig = 23,
+    InsufficientCollateral = 24,
+    AutoCompoundDisabled = 25,
+    CompoundedTooRecently = 26,
+    ValidSbtRequired = 27,
+    ExceedsTierLimit = 28,
+    ConcentrationCapExceeded = 29,
+    AlreadyTokenized = 30,
+    NothingToTokenize = 31,
+    InsufficientLiquidity = 32,
+    StakingPaused = 33,
+    OnlyAdminCanUnpause = 34,
+    ClaimingPaused = 35,
+    FeeIntakePaused = 36,
+    StakeFrozen = 37,
+    AlreadyEjected = 38,
+    IdentityStillValid = 39,
+    StakeNotFrozen = 40,
+    InvalidBacking = 41,
+    NoBackers = 42,
+    VaultDepleted = 43,
+}
 
 #[contracttype]
 #[derive(Clone)]
@@ -9,232 +76,3312 @@ pub struct Stake {
     pub time_bound: u64,           // Unix timestamp
     pub staked_at: u64,
     pub accumulated_fees: i128,
+    pub total_fees_earned: i128,   // Lifetime fees, not reset on claim
+    pub allocated: i128,           // Portion of spending_limit backing live rails
     pub is_active: bool,
+    pub unbonding_since: Option<u64>, // Set while waiting for allocations to clear after unstake
+    pub collateral: i128,          // Deposited collateral token, in stroops
+    pub total_slashed: i128,
+    pub tier: u32,                 // SBT assurance level when last staked or updated
+    pub auto_compound: bool,       // Opted in to turning fees into collateral and capacity
+    pub last_compounded_at: u64,
+    pub tokenized: bool,           // Collateral and fees belong to holders of the stake's shares
+    pub frozen: bool,              // Ejected for an invalid SBT; earnings held pending review
+}
+
+/// A penalty applied to a staker's collateral and capacity
+#[contracttype]
+#[derive(Clone)]
+pub struct SlashRecord {
+    pub amount: i128,              // Collateral taken
+    pub capacity_reduction: i128,  // Spending limit removed
+    pub collateral_shortfall: i128, // Collateral still missing for capacity backing live rails
+    pub reason: String,
+    pub slashed_by: Address,
+    pub slashed_at: u64,
+}
+
+/// Registries of stakers kept as indexed, swap-removable sets
+#[contracttype]
+#[derive(Clone)]
+pub enum StakerSet {
+    Active,
+    Unbonding,
+}
+
+/// Staking parameters tunable by the admin
+#[contracttype]
+#[derive(Clone)]
+pub struct StakingConfig {
+    pub min_spending_limit: i128,
+    pub max_spending_limit: i128,
+    pub min_time_bound: u64,           // Minimum seconds between now and a stake's time bound
+    pub max_time_bound: u64,           // Maximum seconds between now and a stake's time bound
+    pub collateral_ratio_bps: u32,     // Collateral required per unit of spending limit
+    pub tier_limits: Vec<i128>,        // Max spending limit per KYC tier, starting at tier 1
+    pub max_backer_share_bps: u32,     // Largest share of one allocation a single stake may take, 0 for no cap
+}
+
+/// Which agents a staker is willing to back
+#[contracttype]
+#[derive(Clone)]
+pub struct StakePolicy {
+    pub allowed: Vec<Address>,          // Agents or principals; empty allows everyone not denied
+    pub denied: Vec<Address>,           // Agents or principals never backed
+    pub max_exposure_per_agent: i128,   // 0 for no cap
+    pub max_rail_duration: u64,         // Seconds, 0 for no cap
+    pub min_agent_tier: u32,            // Minimum SBT assurance level of the agent
+}
+
+/// A finished stake period, archived when the stake is deactivated
+#[contracttype]
+#[derive(Clone)]
+pub struct StakePeriod {
+    pub started_at: u64,
+    pub ended_at: u64,
+    pub spending_limit: i128,
+    pub fees_earned: i128,
+}
+
+/// Per-epoch totals used for trailing yield calculations
+#[contracttype]
+#[derive(Clone)]
+pub struct EpochCheckpoint {
+    pub fees: i128,                // Fees credited to stakers during the epoch
+    pub opening_capacity: i128,    // Pool capacity before the first change in the epoch
+    pub closing_capacity: i128,    // Pool capacity after the last change in the epoch
+}
+
+/// Totals for the stakes whose time bound falls in one epoch
+#[contracttype]
+#[derive(Clone)]
+pub struct ExpiryBucket {
+    pub count: u32,
+    pub capacity: i128,            // Sum of spending limits
+    pub allocated: i128,           // Sum of allocations
+}
+
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub enum LedgerKind {
+    Credit,                        // Fee earned from a rail
+    Claim,                         // Fees paid out to the staker
+    Compound,                      // Fees turned into collateral
+    VaultCredit,                   // Fee earned from a rail by a tokenized stake, paid into its vault
+    Tokenized,                     // Unclaimed fees moved into the stake's vault on tokenizing
+}
+
+#[contracttype]
+#[derive(Clone)]
+pub struct LedgerEntry {
+    pub kind: LedgerKind,
+    pub rail_id: Option<BytesN<32>>, // Rail that paid the fee, for credits
+    pub amount: i128,
+    pub timestamp: u64,
+    pub total_credited: i128,      // Running totals up to and including this entry
+    pub total_claimed: i128,
+    pub total_compounded: i128,
+    pub total_vaulted: i128,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct EarningsTotals {
+    pub credited: i128,
+    pub claimed: i128,
+    pub compounded: i128,
+    pub vaulted: i128,             // Fees that went to the stake's share holders instead
+}
+
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PauseFlags {
+    pub staking: bool,             // Staking, resizing, collateral deposits, tokenizing
+    pub claiming: bool,            // Claims, compounding, share redemption
+    pub fee_intake: bool,          // Fee credits from the Dharma Pool
+}
+
+/// The assets behind one tokenized stake's shares. Each tokenized stake is its own
+/// share class, so its fees and slashes reach only the holders of its shares
+#[contracttype]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShareVault {
+    pub assets: i128,              // Collateral and fees of the stake
+    pub liquid: i128,              // Part of `assets` that can be redeemed now
+    pub shares: i128,              // Supply of the stake's shares
+}
+
+#[contracttype]
+#[derive(Clone)]
+pub struct ShareAllowance {
+    pub amount: i128,
+    pub expiration_ledger: u32,
+}
+
+#[contracttype]
+#[derive(Clone)]
+pub struct Backing {
+    pub staker: Address,
+    pub amount: i128,              // Capacity reserved from this stake
 }
 
 #[contracttype]
 pub enum DataKey {
     Stake(Address),
+    Admin,
+    Config,
     SBTContract,
+    DharmaPool,
+    Arbiter,               // Optional role allowed to slash besides the Dharma Pool
+    Pauser,                // Optional role allowed to pause entry points
+    Paused,
+    CollateralToken,
+    InsuranceFund,         // Receives slashed collateral; burned when unset
     TotalCapacity,
-    ActiveStakers,
+    TotalAllocated,
+    SetCount(StakerSet),            // Number of stakers in a registry
+    SetAt(StakerSet, u32),          // Registry slot -> staker
+    SetIndex(StakerSet, Address),   // Staker -> registry slot
+    Epoch(u64),
+    ExpiryBucket(u64),     // Epoch -> totals of stakes expiring in it
+    ExpiryAt(u64, u32),    // (Epoch, slot) -> staker
+    ExpirySlot(Address),   // Staker -> slot in its expiry bucket
+    ExpiryEpochs,          // Sorted epochs that have a non-empty expiry bucket
+    HistoryCount(Address), // Number of archived stake periods
+    History(Address, u32), // (Staker, index) -> archived stake period
+    SlashCount(Address),
+    Slash(Address, u32),   // (Staker, index) -> slash record
+    Vault(Address),        // Tokenized stake -> share vault
+    ShareBalance(Address, Address),   // (Stake, holder) -> shares
+    ShareAllowance(Address, Address, Address), // (Stake, owner, spender) -> allowance
+    LedgerCount(Address),
+    Ledger(Address, u32),  // (Staker, index) -> earnings ledger entry, in time order
+    Policy(Address),
+    Exposure(Address, Address), // (Staker, agent) -> capacity reserved for the agent
+    Operator(Address),     // Staker -> hot key allowed to manage the stake
+    AllocationCursor,      // Active registry slot the next allocation starts scanning from
 }
 
-#[contract]
-pub struct IdentityPoolContract;
+const EPOCH_LENGTH: u64 = 86_400;              // 1 day
+const DAYS_PER_YEAR: i128 = 365;
+const SECONDS_PER_YEAR: i128 = 31_536_000;
+const BPS_DENOMINATOR: i128 = 10_000;
+const APY_SHORT_WINDOW: u32 = 7;               // In epochs (days)
+const APY_LONG_WINDOW: u32 = 30;
+const MAX_PAGE_SIZE: u32 = 100;
+const MAX_POLICY_LIST: u32 = 50;
+const MAX_ALLOCATION_CANDIDATES: u32 = 20;     // Stakes an allocation spreads over when they suffice
+const MAX_ALLOCATION_SCAN: u32 = 40;           // Stakes an allocation may read to cover its amount
+const COMPOUND_INTERVAL: u64 = EPOCH_LENGTH;            // Minimum time between compoundings
+// Amounts are in USDC units of 7 decimals
+const DEFAULT_MIN_SPENDING_LIMIT: i128 = 1_000_000;                 // 0.1 USDC
+const DEFAULT_MAX_SPENDING_LIMIT: i128 = 10_000_000_000_000;        // 1,000,000 USDC
+const DEFAULT_BASIC_TIER_LIMIT: i128 = 10_000_000_000;              // 1,000 USDC
+const DEFAULT_ENHANCED_TIER_LIMIT: i128 = 100_000_000_000;          // 10,000 USDC
+const DEFAULT_INSTITUTIONAL_TIER_LIMIT: i128 = 10_000_000_000_000;  // 1,000,000 USDC
+const DEFAULT_MIN_TIME_BOUND: u64 = 3_600;             // 1 hour
+const DEFAULT_MAX_TIME_BOUND: u64 = 31_536_000;        // 1 year
 
-#[contractimpl]
-impl IdentityPoolContract {
-    /// Initialize the contract with SBT contract address
-    pub fn initialize(env: Env, sbt_contract: Address) {
-        if env.storage().instance().has(&DataKey::SBTContract) {
-            panic!("Already initialized");
+/// Apply a capacity change and fee credit to the pool totals and the current epoch checkpoint
+fn record_epoch(env: &Env, capacity_delta: i128, fees: i128) {
+    let capacity: i128 = env.storage().instance().get(&DataKey::TotalCapacity).unwrap_or(0);
+    let epoch = env.ledger().timestamp() / EPOCH_LENGTH;
+
+    let mut checkpoint = env.storage().persistent()
+        .get::<DataKey, EpochCheckpoint>(&DataKey::Epoch(epoch))
+        .unwrap_or(EpochCheckpoint {
+            fees: 0,
+            opening_capacity: capacity,
+            closing_capacity: capacity,
+        });
+    checkpoint.fees += fees;
+    checkpoint.closing_capacity = capacity + capacity_delta;
+    env.storage().persistent().set(&DataKey::Epoch(epoch), &checkpoint);
+
+    if capacity_delta != 0 {
+        env.storage().instance().set(&DataKey::TotalCapacity, &(capacity + capacity_delta));
+    }
+}
+
+/// Append a staker to a registry
+fn set_insert(env: &Env, set: StakerSet, staker: &Address) {
+    let count: u32 = env.storage().instance().get(&DataKey::SetCount(set.clone())).unwrap_or(0);
+    env.storage().persistent().set(&DataKey::SetAt(set.clone(), count), staker);
+    env.storage().persistent().set(&DataKey::SetIndex(set.clone(), staker.clone()), &count);
+    env.storage().instance().set(&DataKey::SetCount(set), &(count + 1));
+}
+
+/// Remove a staker from a registry by moving the last entry into its slot
+fn set_remove(env: &Env, set: StakerSet, staker: &Address) {
+    let index: u32 = match env.storage().persistent().get(&DataKey::SetIndex(set.clone(), staker.clone())) {
+        Some(index) => index,
+        None => return,
+    };
+    let last: u32 = env.storage().instance().get::<DataKey, u32>(&DataKey::SetCount(set.clone())).unwrap_or(1) - 1;
+
+    if index != last {
+        let moved: Address = env.storage().persistent().get(&DataKey::SetAt(set.clone(), last)).unwrap();
+        env.storage().persistent().set(&DataKey::SetAt(set.clone(), index), &moved);
+        env.storage().persistent().set(&DataKey::SetIndex(set.clone(), moved), &index);
+    }
+    env.storage().persistent().remove(&DataKey::SetAt(set.clone(), last));
+    env.storage().persistent().remove(&DataKey::SetIndex(set.clone(), staker.clone()));
+    env.storage().instance().set(&DataKey::SetCount(set), &last);
+}
+
+fn set_len(env: &Env, set: StakerSet) -> u32 {
+    env.storage().instance().get(&DataKey::SetCount(set)).unwrap_or(0)
+}
+
+/// A page of a registry (at most MAX_PAGE_SIZE entries)
+fn set_page(env: &Env, set: StakerSet, offset: u32, limit: u32) -> Vec<Address> {
+    let end = offset.saturating_add(limit.min(MAX_PAGE_SIZE)).min(set_len(env, set.clone()));
+
+    let mut stakers = Vec::new(env);
+    for index in offset..end {
+        if let Some(staker) = env.storage().persistent().get::<DataKey, Address>(&DataKey::SetAt(set.clone(), index)) {
+            stakers.push_back(staker);
         }
-        env.storage().instance().set(&DataKey::SBTContract, &sbt_contract);
-        env.storage().instance().set(&DataKey::TotalCapacity, &0i128);
-        
-        let empty_vec: Vec<Address> = Vec::new(&env);
-        env.storage().instance().set(&DataKey::ActiveStakers, &empty_vec);
     }
+    stakers
+}
 
-    /// Stake identity with spending limits
-    pub fn stake_identity(
-        env: Env,
-        staker: Address,
-        spending_limit: i128,
-        time_bound: u64,
-    ) -> Result<(), String> {
-        staker.require_auth();
+/// Whether a stake can back rails and earn fees right now
+fn is_live(env: &Env, stake: &Stake) -> bool {
+    stake.is_active && env.ledger().timestamp() < stake.time_bound
+}
+
+/// Add an active stake to the bucket of the epoch it expires in
+fn add_to_expiry_bucket(env: &Env, stake: &Stake) {
+    let epoch = stake.time_bound / EPOCH_LENGTH;
+    let mut bucket = env.storage().persistent()
+        .get::<DataKey, ExpiryBucket>(&DataKey::ExpiryBucket(epoch))
+        .unwrap_or_else(|| {
+            let mut epochs: Vec<u64> = env.storage().persistent()
+                .get(&DataKey::ExpiryEpochs)
+                .unwrap_or(Vec::new(env));
+            if let Err(index) = epochs.binary_search(epoch) {
+                epochs.insert(index, epoch);
+            }
+            env.storage().persistent().set(&DataKey::ExpiryEpochs, &epochs);
+            ExpiryBucket { count: 0, capacity: 0, allocated: 0 }
+        });
+
+    env.storage().persistent().set(&DataKey::ExpiryAt(epoch, bucket.count), &stake.staker);
+    env.storage().persistent().set(&DataKey::ExpirySlot(stake.staker.clone()), &bucket.count);
+    bucket.count += 1;
+    bucket.capacity += stake.spending_limit;
+    bucket.allocated += stake.allocated;
+    env.storage().persistent().set(&DataKey::ExpiryBucket(epoch), &bucket);
+}
+
+/// Remove a stake from its expiry bucket by moving the last entry into its slot
+fn remove_from_expiry_bucket(env: &Env, stake: &Stake) {
+    let epoch = stake.time_bound / EPOCH_LENGTH;
+    let mut bucket: ExpiryBucket = match env.storage().persistent().get(&DataKey::ExpiryBucket(epoch)) {
+        Some(bucket) => bucket,
+        None => return,
+    };
+    let slot: u32 = match env.storage().persistent().get(&DataKey::ExpirySlot(stake.staker.clone())) {
+        Some(slot) => slot,
+        None => return,
+    };
+    let last = bucket.count - 1;
 
-        // Validate spending limits (100-10,000 USDC = 1,000,000 - 100,000,000 stroops)
-        if spending_limit < 1_000_000 || spending_limit > 100_000_000 {
-            return Err(String::from_str(&env, "Invalid spending limit"));
+    if slot != last {
+        let moved: Address = env.storage().persistent().get(&DataKey::ExpiryAt(epoch, last)).unwrap();
+        env.storage().persistent().set(&DataKey::ExpiryAt(epoch, slot), &moved);
+        env.storage().persistent().set(&DataKey::ExpirySlot(moved), &slot);
+    }
+    env.storage().persistent().remove(&DataKey::ExpiryAt(epoch, last));
+    env.storage().persistent().remove(&DataKey::ExpirySlot(stake.staker.clone()));
+
+    if last == 0 {
+        env.storage().persistent().remove(&DataKey::ExpiryBucket(epoch));
+        let mut epochs: Vec<u64> = env.storage().persistent()
+            .get(&DataKey::ExpiryEpochs)
+            .unwrap_or(Vec::new(env));
+        if let Ok(index) = epochs.binary_search(epoch) {
+            epochs.remove(index);
         }
+        env.storage().persistent().set(&DataKey::ExpiryEpochs, &epochs);
+    } else {
+        bucket.count = last;
+        bucket.capacity -= stake.spending_limit;
+        bucket.allocated -= stake.allocated;
+        env.storage().persistent().set(&DataKey::ExpiryBucket(epoch), &bucket);
+    }
+}
+
+fn adjust_bucket_allocated(env: &Env, time_bound: u64, delta: i128) {
+    let key = DataKey::ExpiryBucket(time_bound / EPOCH_LENGTH);
+    if let Some(mut bucket) = env.storage().persistent().get::<DataKey, ExpiryBucket>(&key) {
+        bucket.allocated += delta;
+        env.storage().persistent().set(&key, &bucket);
+    }
+}
+
+/// Capacity and allocations of unswept stakes expiring in the current epoch or earlier
+fn expired_totals(env: &Env) -> (i128, i128) {
+    let current_epoch = env.ledger().timestamp() / EPOCH_LENGTH;
+    let epochs: Vec<u64> = env.storage().persistent()
+        .get(&DataKey::ExpiryEpochs)
+        .unwrap_or(Vec::new(env));
 
-        // Check if already staked
-        if env.storage().persistent().has(&DataKey::Stake(staker.clone())) {
-            return Err(String::from_str(&env, "Already staked"));
+    let mut capacity = 0i128;
+    let mut allocated = 0i128;
+    for epoch in epochs.iter() {
+        if epoch > current_epoch {
+            break;
         }
+        let bucket: ExpiryBucket = env.storage().persistent().get(&DataKey::ExpiryBucket(epoch)).unwrap();
+        capacity += bucket.capacity;
+        allocated += bucket.allocated;
+    }
+    (capacity, allocated)
+}
 
-        // TODO: In production, verify SBT with cross-contract call
-        // For demo, we skip this check
+fn get_config(env: &Env) -> StakingConfig {
+    env.storage().instance().get(&DataKey::Config).unwrap()
+}
 
-        let stake = Stake {
-            staker: staker.clone(),
-            spending_limit,
-            time_bound,
-            staked_at: env.ledger().timestamp(),
-            accumulated_fees: 0,
-            is_active: true,
-        };
+fn validate_config(config: &StakingConfig) -> Result<(), PoolError> {
+    if config.min_spending_limit <= 0 || config.min_spending_limit > config.max_spending_limit {
+        return Err(PoolError::InvalidConfig);
+    }
+    if config.min_time_bound > config.max_time_bound {
+        return Err(PoolError::InvalidConfig);
+    }
+    if config.tier_limits.iter().any(|limit| limit <= 0) {
+        return Err(PoolError::InvalidConfig);
+    }
+    if config.max_backer_share_bps as i128 > BPS_DENOMINATOR {
+        return Err(PoolError::InvalidConfig);
+    }
+    Ok(())
+}
 
-        env.storage().persistent().set(&DataKey::Stake(staker.clone()), &stake);
+fn get_pause_flags(env: &Env) -> PauseFlags {
+    env.storage().instance().get(&DataKey::Paused).unwrap_or(PauseFlags {
+        staking: false,
+        claiming: false,
+        fee_intake: false,
+    })
+}
 
-        // Update total capacity
-        let mut total_capacity: i128 = env.storage().instance().get(&DataKey::TotalCapacity).unwrap_or(0);
-        total_capacity += spending_limit;
-        env.storage().instance().set(&DataKey::TotalCapacity, &total_capacity);
+fn get_vault(env: &Env, stake: &Address) -> ShareVault {
+    env.storage().persistent()
+        .get(&DataKey::Vault(stake.clone()))
+        .unwrap_or(ShareVault { assets: 0, liquid: 0, shares: 0 })
+}
 
-        // Add to active stakers
-        let mut active_stakers: Vec<Address> = env.storage().instance()
-            .get(&DataKey::ActiveStakers)
-            .unwrap_or(Vec::new(&env));
-        active_stakers.push_back(staker);
-        env.storage().instance().set(&DataKey::ActiveStakers, &active_stakers);
+fn set_vault(env: &Env, stake: &Address, vault: &ShareVault) {
+    env.storage().persistent().set(&DataKey::Vault(stake.clone()), vault);
+}
 
-        Ok(())
-    }
+fn share_balance(env: &Env, stake: &Address, id: &Address) -> i128 {
+    env.storage().persistent().get(&DataKey::ShareBalance(stake.clone(), id.clone())).unwrap_or(0)
+}
 
-    /// Unstake identity and withdraw fees
-    pub fn unstake_identity(env: Env, staker: Address) -> Result<i128, String> {
-        staker.require_auth();
+fn set_share_balance(env: &Env, stake: &Address, id: &Address, amount: i128) {
+    env.storage().persistent().set(&DataKey::ShareBalance(stake.clone(), id.clone()), &amount);
+}
 
-        if let Some(mut stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone())) {
-            stake.is_active = false;
-            let fees = stake.accumulated_fees;
+/// Add `amount` of assets to a stake's vault and mint its shares to the staker at the
+/// current price. A vault slashed to nothing while shares are out takes no deposits,
+/// since they would be diluted by the worthless shares
+fn vault_deposit(env: &Env, stake: &Address, amount: i128, liquid: i128) -> Result<i128, PoolError> {
+    let mut vault = get_vault(env, stake);
+    let shares = if vault.shares == 0 {
+        amount
+    } else if vault.assets <= 0 {
+        return Err(PoolError::VaultDepleted);
+    } else {
+        amount * vault.shares / vault.assets
+    };
+    vault.assets += amount;
+    vault.liquid += liquid;
+    vault.shares += shares;
+    set_vault(env, stake, &vault);
 
-            // Update total capacity
-            let mut total_capacity: i128 = env.storage().instance().get(&DataKey::TotalCapacity).unwrap_or(0);
-            total_capacity -= stake.spending_limit;
-            env.storage().instance().set(&DataKey::TotalCapacity, &total_capacity);
+    set_share_balance(env, stake, stake, share_balance(env, stake, stake) + shares);
+    env.events().publish((symbol_short!("mint"), stake.clone(), stake.clone()), shares);
+    Ok(shares)
+}
 
-            // Remove from active stakers
-            let mut active_stakers: Vec<Address> = env.storage().instance()
-                .get(&DataKey::ActiveStakers)
-                .unwrap_or(Vec::new(&env));
-            if let Some(index) = active_stakers.iter().position(|a| a == staker.clone()) {
-                active_stakers.remove(index as u32);
-            }
-            env.storage().instance().set(&DataKey::ActiveStakers, &active_stakers);
+fn spend_share_allowance(env: &Env, stake: &Address, from: &Address, spender: &Address, amount: i128) {
+    let key = DataKey::ShareAllowance(stake.clone(), from.clone(), spender.clone());
+    let allowance: ShareAllowance = env.storage().persistent()
+        .get(&key)
+        .unwrap_or(ShareAllowance { amount: 0, expiration_ledger: 0 });
+    if allowance.expiration_ledger < env.ledger().sequence() || allowance.amount < amount {
+        panic!("insufficient allowance");
+    }
+    env.storage().persistent().set(&key, &ShareAllowance {
+        amount: allowance.amount - amount,
+        expiration_ledger: allowance.expiration_ledger,
+    });
+}
 
-            env.storage().persistent().set(&DataKey::Stake(staker), &stake);
+/// Shares held by a frozen staker stay put pending review, like its unclaimed fees
+fn shares_frozen(env: &Env, holder: &Address) -> bool {
+    env.storage().persistent()
+        .get::<DataKey, Stake>(&DataKey::Stake(holder.clone()))
+        .is_some_and(|stake| stake.frozen)
+}
 
-            Ok(fees)
-        } else {
-            Err(String::from_str(&env, "Stake not found"))
-        }
+fn move_shares(env: &Env, stake: &Address, from: &Address, to: &Address, amount: i128) {
+    if shares_frozen(env, from) {
+        panic!("shares frozen");
     }
+    let balance = share_balance(env, stake, from);
+    if amount < 0 || balance < amount {
+        panic!("insufficient balance");
+    }
+    set_share_balance(env, stake, from, balance - amount);
+    set_share_balance(env, stake, to, share_balance(env, stake, to) + amount);
+    env.events().publish((symbol_short!("transfer"), stake.clone(), from.clone(), to.clone()), amount);
+}
 
-    /// Get available capacity in the pool
-    pub fn get_available_capacity(env: Env) -> i128 {
-        env.storage().instance().get(&DataKey::TotalCapacity).unwrap_or(0)
+fn burn_shares(env: &Env, stake: &Address, from: &Address, amount: i128) {
+    if shares_frozen(env, from) {
+        panic!("shares frozen");
+    }
+    let balance = share_balance(env, stake, from);
+    if amount < 0 || balance < amount {
+        panic!("insufficient balance");
     }
+    set_share_balance(env, stake, from, balance - amount);
+    let mut vault = get_vault(env, stake);
+    vault.shares -= amount;
+    set_vault(env, stake, &vault);
+    env.events().publish((symbol_short!("burn"), stake.clone(), from.clone()), amount);
+}
 
-    /// Get stake details
-    pub fn get_stake(env: Env, staker: Address) -> Option<Stake> {
-        env.storage().persistent().get(&DataKey::Stake(staker))
+/// Credit fees from a rail to a live stake
+fn credit_fees(env: &Env, staker: &Address, amount: i128, rail_id: &BytesN<32>) -> Result<(), PoolError> {
+    let mut stake: Stake = env.storage().persistent()
+        .get(&DataKey::Stake(staker.clone()))
+        .ok_or(PoolError::StakeNotFound)?;
+    if !is_live(env, &stake) {
+        return Err(PoolError::StakeNotActive);
     }
 
-    /// Calculate mock APY (15% for demo)
-    pub fn calculate_apy(env: Env) -> u32 {
-        1500 // 15.00% in basis points
+    // Fees of a tokenized stake raise the price of its shares instead
+    if stake.tokenized {
+        let mut vault = get_vault(env, staker);
+        vault.assets += amount;
+        vault.liquid += amount;
+        set_vault(env, staker, &vault);
+    } else {
+        stake.accumulated_fees += amount;
     }
+    stake.total_fees_earned += amount;
+    let kind = if stake.tokenized { LedgerKind::VaultCredit } else { LedgerKind::Credit };
+    record_ledger(env, staker, kind, Some(rail_id.clone()), amount);
+    env.storage().persistent().set(&DataKey::Stake(staker.clone()), &stake);
 
-    /// Claim accumulated earnings
-    pub fn claim_earnings(env: Env, staker: Address) -> Result<i128, String> {
-        staker.require_auth();
+    record_epoch(env, 0, amount);
+    Ok(())
+}
 
-        if let Some(mut stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone())) {
-            let fees = stake.accumulated_fees;
-            stake.accumulated_fees = 0;
-            env.storage().persistent().set(&DataKey::Stake(staker), &stake);
-            Ok(fees)
-        } else {
-            Err(String::from_str(&env, "Stake not found"))
-        }
+/// Append an entry to a staker's earnings ledger
+fn record_ledger(env: &Env, staker: &Address, kind: LedgerKind, rail_id: Option<BytesN<32>>, amount: i128) {
+    let count: u32 = env.storage().persistent().get(&DataKey::LedgerCount(staker.clone())).unwrap_or(0);
+    let mut totals = ledger_totals_before(env, staker, count, u64::MAX);
+    match kind {
+        LedgerKind::Credit => totals.credited += amount,
+        LedgerKind::Claim => totals.claimed += amount,
+        LedgerKind::Compound => totals.compounded += amount,
+        LedgerKind::VaultCredit | LedgerKind::Tokenized => totals.vaulted += amount,
     }
 
-    /// Add fees to a staker (called by Dharma Pool)
-    pub fn add_fees(env: Env, staker: Address, amount: i128) -> Result<(), String> {
-        // In production, verify caller is Dharma Pool contract
-        
-        if let Some(mut stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone())) {
-            stake.accumulated_fees += amount;
-            env.storage().persistent().set(&DataKey::Stake(staker), &stake);
-            Ok(())
+    let entry = LedgerEntry {
+        kind,
+        rail_id,
+        amount,
+        timestamp: env.ledger().timestamp(),
+        total_credited: totals.credited,
+        total_claimed: totals.claimed,
+        total_compounded: totals.compounded,
+        total_vaulted: totals.vaulted,
+    };
+    env.storage().persistent().set(&DataKey::Ledger(staker.clone(), count), &entry);
+    env.storage().persistent().set(&DataKey::LedgerCount(staker.clone()), &(count + 1));
+}
+
+/// Running ledger totals over entries strictly before `timestamp` (binary search)
+fn ledger_totals_before(env: &Env, staker: &Address, count: u32, timestamp: u64) -> EarningsTotals {
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = (low + high) / 2;
+        let entry: LedgerEntry = env.storage().persistent().get(&DataKey::Ledger(staker.clone(), mid)).unwrap();
+        if entry.timestamp < timestamp {
+            low = mid + 1;
         } else {
-            Err(String::from_str(&env, "Stake not found"))
+            high = mid;
         }
     }
 
-    /// Get all active stakers
-    pub fn get_active_stakers(env: Env) -> Vec<Address> {
-        env.storage().instance()
-            .get(&DataKey::ActiveStakers)
-            .unwrap_or(Vec::new(&env))
+    if low == 0 {
+        return EarningsTotals { credited: 0, claimed: 0, compounded: 0, vaulted: 0 };
+    }
+    let last: LedgerEntry = env.storage().persistent().get(&DataKey::Ledger(staker.clone(), low - 1)).unwrap();
+    EarningsTotals {
+        credited: last.total_credited,
+        claimed: last.total_claimed,
+        compounded: last.total_compounded,
+        vaulted: last.total_vaulted,
     }
+}
 
-    /// Check if staker is active
-    pub fn is_active(env: Env, staker: Address) -> bool {
-        if let Some(stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker)) {
-            stake.is_active && env.ledger().timestamp() < stake.time_bound
+/// Collateral the config requires behind a spending limit
+fn required_collateral(config: &StakingConfig, spending_limit: i128) -> i128 {
+    spending_limit * config.collateral_ratio_bps as i128 / BPS_DENOMINATOR
+}
+
+/// Capacity of a live stake that could back `agent` for `duration`, or 0 if ineligible
+fn backable_capacity(env: &Env, stake: &Stake, profile: &AgentProfile, duration: u64) -> i128 {
+    if !is_live(env, stake) || stake.time_bound < env.ledger().timestamp() + duration {
+        return 0;
+    }
+    let mut free = stake.spending_limit - stake.allocated;
+    if let Some(policy) = env.storage().persistent().get::<DataKey, StakePolicy>(&DataKey::Policy(stake.staker.clone())) {
+        if check_policy(env, &policy, &stake.staker, profile, 0, duration).is_err() {
+            return 0;
+        }
+        if policy.max_exposure_per_agent > 0 {
+            let exposure: i128 = env.storage().persistent()
+                .get(&DataKey::Exposure(stake.staker.clone(), profile.agent.clone()))
+                .unwrap_or(0);
+            free = free.min(policy.max_exposure_per_agent - exposure);
+        }
+    }
+    free.max(0)
+}
+
+/// Reserve part of a stake for an agent; the caller updates `TotalAllocated`
+fn reserve_stake(env: &Env, stake: &mut Stake, agent: &Address, amount: i128) {
+    let exposure_key = DataKey::Exposure(stake.staker.clone(), agent.clone());
+    let exposure: i128 = env.storage().persistent().get(&exposure_key).unwrap_or(0);
+    env.storage().persistent().set(&exposure_key, &(exposure + amount));
+
+    stake.allocated += amount;
+    env.storage().persistent().set(&DataKey::Stake(stake.staker.clone()), stake);
+    adjust_bucket_allocated(env, stake.time_bound, amount);
+}
+
+/// Return capacity reserved for an agent to a stake
+fn release_stake(env: &Env, staker: &Address, agent: &Address, amount: i128) -> Result<(), PoolError> {
+    if let Some(mut stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone())) {
+        let exposure_key = DataKey::Exposure(staker.clone(), agent.clone());
+        let exposure: i128 = env.storage().persistent().get(&exposure_key).unwrap_or(0);
+        if amount <= 0 || amount > exposure {
+            return Err(PoolError::InvalidAmount);
+        }
+
+        if exposure == amount {
+            env.storage().persistent().remove(&exposure_key);
         } else {
-            false
+            env.storage().persistent().set(&exposure_key, &(exposure - amount));
+        }
+
+        stake.allocated -= amount;
+
+        // Inactive stakes were already taken out of the pool totals
+        if stake.is_active {
+            let total_allocated: i128 = env.storage().instance().get(&DataKey::TotalAllocated).unwrap_or(0);
+            env.storage().instance().set(&DataKey::TotalAllocated, &(total_allocated - amount));
+            adjust_bucket_allocated(env, stake.time_bound, -amount);
         }
+
+        env.storage().persistent().set(&DataKey::Stake(staker.clone()), &stake);
+        Ok(())
+    } else {
+        Err(PoolError::StakeNotFound)
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use soroban_sdk::{testutils::Address as _, Env};
+/// Split `amount` pro-rata over the free capacity of eligible stakes other than `exclude`,
+/// starting where the previous allocation stopped. MAX_ALLOCATION_CANDIDATES stakes are
+/// considered, and more up to MAX_ALLOCATION_SCAN while they cannot cover the amount
+fn allocate_backers(
+    env: &Env,
+    amount: i128,
+    agent: &Address,
+    duration: u64,
+    exclude: Option<&Address>,
+) -> Result<Vec<Backing>, PoolError> {
+    if amount <= 0 {
+        return Err(PoolError::InvalidAmount);
+    }
 
-    #[test]
-    fn test_stake_and_unstake() {
-        let env = Env::default();
-        let contract_id = env.register_contract(None, IdentityPoolContract);
-        let client = IdentityPoolContractClient::new(&env, &contract_id);
+    let count = set_len(env, StakerSet::Active);
+    if count == 0 {
+        return Err(PoolError::InsufficientCapacity);
+    }
+    let cursor: u32 = env.storage().instance().get::<DataKey, u32>(&DataKey::AllocationCursor).unwrap_or(0) % count;
+    let profile = agent_profile(env, agent);
 
-        let sbt_contract = Address::generate(&env);
-        let staker = Address::generate(&env);
+    let config = get_config(env);
+    let share_cap = if config.max_backer_share_bps == 0 {
+        amount
+    } else {
+        amount * config.max_backer_share_bps as i128 / BPS_DENOMINATOR
+    };
 
-        client.initialize(&sbt_contract);
+    // Eligible stakes in scan order, each with the capacity it can give this agent
+    let mut candidates: Vec<Stake> = Vec::new(env);
+    let mut capacities: Vec<i128> = Vec::new(env);
+    let mut total_free: i128 = 0;
+    let mut reachable: i128 = 0;          // Free capacity within the concentration cap
+    let mut scanned: u32 = 0;
+    for step in 0..count.min(MAX_ALLOCATION_SCAN) {
+        if step >= MAX_ALLOCATION_CANDIDATES && reachable >= amount {
+            break;
+        }
+        scanned = step + 1;
+        let staker: Address = env.storage().persistent()
+            .get(&DataKey::SetAt(StakerSet::Active, (cursor + step) % count))
+            .unwrap();
+        if exclude == Some(&staker) {
+            continue;
+        }
+        let stake: Stake = env.storage().persistent().get(&DataKey::Stake(staker)).unwrap();
+        let free = backable_capacity(env, &stake, &profile, duration);
+        if free > 0 {
+            candidates.push_back(stake);
+            capacities.push_back(free);
+            total_free += free;
+            reachable += free.min(share_cap);
+        }
+    }
+    env.storage().instance().set(&DataKey::AllocationCursor, &((cursor + scanned) % count));
 
-        env.mock_all_auths();
-        
-        // Stake
-        let result = client.stake_identity(&staker, &10_000_000, &(env.ledger().timestamp() + 86400));
-        assert!(result.is_ok());
+    if total_free < amount {
+        return Err(PoolError::InsufficientCapacity);
+    }
 
-        // Check capacity
-        assert_eq!(client.get_available_capacity(), 10_000_000);
+    // Pro-rata shares rounded down, then the remainder in scan order
+    let mut shares: Vec<i128> = Vec::new(env);
+    let mut assigned: i128 = 0;
+    for free in capacities.iter() {
+        let share = (amount * free / total_free).min(share_cap);
+        shares.push_back(share);
+        assigned += share;
+    }
+    for i in 0..shares.len() {
+        if assigned == amount {
+            break;
+        }
+        let share = shares.get(i).unwrap();
+        let extra = (capacities.get(i).unwrap().min(share_cap) - share).min(amount - assigned);
+        shares.set(i, share + extra);
+        assigned += extra;
+    }
+    if assigned < amount {
+        return Err(PoolError::ConcentrationCapExceeded);
+    }
 
-        // Check stake
-        let stake = client.get_stake(&staker).unwrap();
-        assert_eq!(stake.spending_limit, 10_000_000);
-        assert!(stake.is_active);
+    let mut backings = Vec::new(env);
+    for i in 0..candidates.len() {
+        let share = shares.get(i).unwrap();
+        if share == 0 {
+            continue;
+        }
+        let mut stake = candidates.get(i).unwrap();
+        reserve_stake(env, &mut stake, agent, share);
+        backings.push_back(Backing { staker: stake.staker, amount: share });
+    }
 
-        // Unstake
-        let fees = client.unstake_identity(&staker).unwrap();
-        assert_eq!(fees, 0);
-        assert_eq!(client.get_available_capacity(), 0);
+    let total_allocated: i128 = env.storage().instance().get(&DataKey::TotalAllocated).unwrap_or(0);
+    env.storage().instance().set(&DataKey::TotalAllocated, &(total_allocated + amount));
+    Ok(backings)
+}
+
+/// Look up a staker's KYC tier from their SBT
+fn staker_tier(env: &Env, staker: &Address) -> Result<u32, PoolError> {
+    let sbt_contract: Address = env.storage().instance().get(&DataKey::SBTContract).unwrap();
+    let sbt = SbtClient::new(env, &sbt_contract);
+
+    if !sbt.verify_sbt(staker) {
+        return Err(PoolError::ValidSbtRequired);
+    }
+    match sbt.get_assurance_level(staker) {
+        0 => Err(PoolError::ValidSbtRequired),
+        tier => Ok(tier),
     }
+}
 
-    #[test]
-    fn test_fee_accumulation() {
-        let env = Env::default();
-        let contract_id = env.register_contract(None, IdentityPoolContract);
-        let client = IdentityPoolContractClient::new(&env, &contract_id);
+/// Highest spending limit a tier may stake; tiers past the list use its last entry
+fn tier_limit(config: &StakingConfig, tier: u32) -> i128 {
+    let count = config.tier_limits.len();
+    if count == 0 || tier == 0 {
+        return config.max_spending_limit;
+    }
+    config.tier_limits.get(tier.min(count) - 1).unwrap().min(config.max_spending_limit)
+}
 
-        let sbt_contract = Address::generate(&env);
-        let staker = Address::generate(&env);
+/// Authorize `caller` as the staker or the staker's operator
+fn require_staker_or_operator(env: &Env, staker: &Address, caller: &Address) -> Result<(), PoolError> {
+    caller.require_auth();
 
-        client.initialize(&sbt_contract);
+    if caller == staker {
+        return Ok(());
+    }
+    match env.storage().persistent().get::<DataKey, Address>(&DataKey::Operator(staker.clone())) {
+        Some(operator) if operator == *caller => Ok(()),
+        _ => Err(PoolError::Unauthorized),
+    }
+}
 
-        env.mock_all_auths();
-        client.stake_identity(&staker, &10_000_000, &(env.ledger().timestamp() + 86400)).unwrap();
+/// An agent as seen by staker policies
+struct AgentProfile {
+    agent: Address,
+    principal: Option<Address>,
+    tier: u32,
+}
 
-        // Add fees
-        client.add_fees(&staker, &1_000_000).unwrap();
+/// Look up an agent's principal and KYC tier in the SBT contract
+/// An agent acting for a principal takes the principal's assurance level
+fn agent_profile(env: &Env, agent: &Address) -> AgentProfile {
+    let sbt_contract: Address = env.storage().instance().get(&DataKey::SBTContract).unwrap();
+    let sbt = SbtClient::new(env, &sbt_contract);
 
-        let stake = client.get_stake(&staker).unwrap();
-        assert_eq!(stake.accumulated_fees, 1_000_000);
+    let principal = sbt.get_principal(agent);
+    let tier = sbt.get_assurance_level(principal.as_ref().unwrap_or(agent));
+    AgentProfile { agent: agent.clone(), principal, tier }
+}
 
-        // Claim fees
-        let claimed = client.claim_earnings(&staker).unwrap();
-        assert_eq!(claimed, 1_000_000);
+/// Check a reservation for `profile` against a staker's policy
+fn check_policy(
+    env: &Env,
+    policy: &StakePolicy,
+    staker: &Address,
+    profile: &AgentProfile,
+    amount: i128,
+    duration: u64,
+) -> Result<(), PoolError> {
+    let matches = |list: &Vec<Address>| {
+        list.contains(&profile.agent)
+            || profile.principal.as_ref().is_some_and(|principal| list.contains(principal))
+    };
 
-        let stake = client.get_stake(&staker).unwrap();
-        assert_eq!(stake.accumulated_fees, 0);
+    if matches(&policy.denied) || (!policy.allowed.is_empty() && !matches(&policy.allowed)) {
+        return Err(PoolError::AgentNotAccepted);
+    }
+    if profile.tier < policy.min_agent_tier {
+        return Err(PoolError::AgentTierTooLow);
+    }
+    if policy.max_rail_duration > 0 && duration > policy.max_rail_duration {
+        return Err(PoolError::RailDurationTooLong);
+    }
+    if policy.max_exposure_per_agent > 0 {
+        let exposure: i128 = env.storage().persistent()
+            .get(&DataKey::Exposure(staker.clone(), profile.agent.clone()))
+            .unwrap_or(0);
+        if exposure + amount > policy.max_exposure_per_agent {
+            return Err(PoolError::ExposureLimitExceeded);
+        }
+    }
+    Ok(())
+}
+
+/// Change the limit and time bound of an active stake, keeping pool totals in step
+fn resize_stake(env: &Env, stake: &mut Stake, spending_limit: i128, time_bound: u64) {
+    remove_from_expiry_bucket(env, stake);
+    let capacity_delta = spending_limit - stake.spending_limit;
+    stake.spending_limit = spending_limit;
+    stake.time_bound = time_bound;
+    add_to_expiry_bucket(env, stake);
+
+    // Update total capacity
+    if capacity_delta != 0 {
+        record_epoch(env, capacity_delta, 0);
+    }
+}
+
+/// Pay claimed fees out in the collateral token, which the Dharma Pool settles fees in
+fn pay_fees(env: &Env, staker: &Address, amount: i128) -> Result<(), PoolError> {
+    let token_address: Address = env.storage().instance()
+        .get(&DataKey::CollateralToken)
+        .ok_or(PoolError::CollateralTokenNotSet)?;
+    token::Client::new(env, &token_address).transfer(&env.current_contract_address(), staker, &amount);
+    Ok(())
+}
+
+/// Return a finished stake's collateral to the staker
+fn return_collateral(env: &Env, stake: &mut Stake) {
+    // Tokenized collateral goes back to the stake's share vault for redemption
+    if stake.tokenized {
+        let mut vault = get_vault(env, &stake.staker);
+        vault.liquid += stake.collateral;
+        set_vault(env, &stake.staker, &vault);
+        stake.collateral = 0;
+        stake.tokenized = false;
+    } else if stake.collateral > 0 {
+        let token_address: Address = env.storage().instance().get(&DataKey::CollateralToken).unwrap();
+        token::Client::new(env, &token_address)
+            .transfer(&env.current_contract_address(), &stake.staker, &stake.collateral);
+        stake.collateral = 0;
+    }
+}
+
+/// Take an active stake out of the pool totals, the registry and its expiry bucket
+fn deactivate_stake(env: &Env, stake: &mut Stake) {
+    remove_from_expiry_bucket(env, stake);
+    stake.is_active = false;
+
+    // Update total capacity
+    record_epoch(env, -stake.spending_limit, 0);
+
+    // Allocations stay on the record until released but no longer count against the pool
+    let total_allocated: i128 = env.storage().instance().get(&DataKey::TotalAllocated).unwrap_or(0);
+    env.storage().instance().set(&DataKey::TotalAllocated, &(total_allocated - stake.allocated));
+
+    // Remove from active stakers
+    set_remove(env, StakerSet::Active, &stake.staker);
+
+    // Archive the period; a swept stake ended at its time bound
+    let count: u32 = env.storage().persistent().get(&DataKey::HistoryCount(stake.staker.clone())).unwrap_or(0);
+    let period = StakePeriod {
+        started_at: stake.staked_at,
+        ended_at: env.ledger().timestamp().min(stake.time_bound),
+        spending_limit: stake.spending_limit,
+        fees_earned: stake.total_fees_earned,
+    };
+    env.storage().persistent().set(&DataKey::History(stake.staker.clone(), count), &period);
+    env.storage().persistent().set(&DataKey::HistoryCount(stake.staker.clone()), &(count + 1));
+}
+
+/// Annualized yield in basis points over the trailing `window` epochs (including the current one)
+fn trailing_yield_bps(env: &Env, window: u32) -> u32 {
+    let current_epoch = env.ledger().timestamp() / EPOCH_LENGTH;
+
+    // Walk backwards; an epoch without a checkpoint kept the opening capacity of the next one
+    let mut capacity: i128 = env.storage().instance().get(&DataKey::TotalCapacity).unwrap_or(0);
+    let mut fees = 0i128;
+    let mut capacity_epochs = 0i128;
+
+    for offset in 0..(window as u64).min(current_epoch + 1) {
+        if let Some(checkpoint) = env.storage().persistent()
+            .get::<DataKey, EpochCheckpoint>(&DataKey::Epoch(current_epoch - offset))
+        {
+            fees += checkpoint.fees;
+            capacity_epochs += checkpoint.closing_capacity;
+            capacity = checkpoint.opening_capacity;
+        } else {
+            capacity_epochs += capacity;
+        }
+    }
+
+    if capacity_epochs <= 0 {
+        return 0;
+    }
+    let bps = fees * DAYS_PER_YEAR * BPS_DENOMINATOR / capacity_epochs;
+    bps.clamp(0, u32::MAX as i128) as u32
+}
+
+#[contract]
+pub struct IdentityPoolContract;
+
+#[contractimpl]
+impl IdentityPoolContract {
+    /// Initialize the contract with an admin, SBT and Dharma Pool contract addresses
+    pub fn initialize(env: Env, admin: Address, sbt_contract: Address, dharma_pool: Address) {
+        if env.storage().instance().has(&DataKey::SBTContract) {
+            panic!("Already initialized");
+        }
+        env.storage().instance().set(&DataKey::Admin, &admin);
+        env.storage().instance().set(&DataKey::SBTContract, &sbt_contract);
+        env.storage().instance().set(&DataKey::DharmaPool, &dharma_pool);
+        env.storage().instance().set(&DataKey::TotalCapacity, &0i128);
+        env.storage().instance().set(&DataKey::TotalAllocated, &0i128);
+        env.storage().instance().set(&DataKey::Config, &StakingConfig {
+            min_spending_limit: DEFAULT_MIN_SPENDING_LIMIT,
+            max_spending_limit: DEFAULT_MAX_SPENDING_LIMIT,
+            min_time_bound: DEFAULT_MIN_TIME_BOUND,
+            max_time_bound: DEFAULT_MAX_TIME_BOUND,
+            collateral_ratio_bps: 0,
+            max_backer_share_bps: 0,
+            tier_limits: vec![
+                &env,
+                DEFAULT_BASIC_TIER_LIMIT,
+                DEFAULT_ENHANCED_TIER_LIMIT,
+                DEFAULT_INSTITUTIONAL_TIER_LIMIT,
+            ],
+        });
+    }
+
+    /// Stake identity with spending limits
+    pub fn stake_identity(
+        env: Env,
+        staker: Address,
+        spending_limit: i128,
+        time_bound: u64,
+    ) -> Result<(), PoolError> {
+        if get_pause_flags(&env).staking {
+            return Err(PoolError::StakingPaused);
+        }
+        staker.require_auth();
+
+        let config = get_config(&env);
+        let now = env.ledger().timestamp();
+
+        // Validate spending limits and time bound against the staking config
+        if spending_limit < config.min_spending_limit || spending_limit > config.max_spending_limit {
+            return Err(PoolError::InvalidSpendingLimit);
+        }
+
+        if time_bound < now + config.min_time_bound.max(1) || time_bound > now + config.max_time_bound {
+            return Err(PoolError::InvalidTimeBound);
+        }
+
+        // Cap the limit by how strongly the identity was verified
+        let tier = staker_tier(&env, &staker)?;
+        if spending_limit > tier_limit(&config, tier) {
+            return Err(PoolError::ExceedsTierLimit);
+        }
+
+        // Check if already staked; a previous, finished stake carries over
+        // unclaimed fees and allocations still backing rails
+        let previous = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone()));
+        let (accumulated_fees, allocated, mut collateral, total_slashed, tokenized) = match previous {
+            Some(stake) if stake.is_active => {
+                return Err(PoolError::AlreadyStaked);
+            }
+            Some(stake) if stake.unbonding_since.is_some() => {
+                return Err(PoolError::UnstakePending);
+            }
+            Some(stake) if stake.frozen => {
+                return Err(PoolError::StakeFrozen);
+            }
+            Some(stake) => (
+                stake.accumulated_fees,
+                stake.allocated,
+                stake.collateral,
+                stake.total_slashed,
+                stake.tokenized,
+            ),
+            None => (0, 0, 0, 0, false),
+        };
+
+        if spending_limit < allocated {
+            return Err(PoolError::BelowAllocated);
+        }
+
+        // Pull any collateral the config requires
+        let shortfall = required_collateral(&config, spending_limit) - collateral;
+        if shortfall > 0 {
+            let token_address: Address = env.storage().instance()
+                .get(&DataKey::CollateralToken)
+                .ok_or(PoolError::CollateralTokenNotSet)?;
+            token::Client::new(&env, &token_address)
+                .transfer(&staker, &env.current_contract_address(), &shortfall);
+            collateral += shortfall;
+            if tokenized {
+                vault_deposit(&env, &staker, shortfall, 0)?;
+            }
+        }
+
+        let stake = Stake {
+            staker: staker.clone(),
+            spending_limit,
+            time_bound,
+            staked_at: now,
+            accumulated_fees,
+            total_fees_earned: 0,
+            allocated,
+            is_active: true,
+            unbonding_since: None,
+            collateral,
+            total_slashed,
+            tier,
+            auto_compound: false,
+            last_compounded_at: now,
+            tokenized,
+            frozen: false,
+        };
+
+        env.storage().persistent().set(&DataKey::Stake(staker.clone()), &stake);
+
+        // Update total capacity
+        record_epoch(&env, spending_limit, 0);
+        if allocated > 0 {
+            let total_allocated: i128 = env.storage().instance().get(&DataKey::TotalAllocated).unwrap_or(0);
+            env.storage().instance().set(&DataKey::TotalAllocated, &(total_allocated + allocated));
+        }
+
+        // Add to active stakers
+        set_insert(&env, StakerSet::Active, &staker);
+        add_to_expiry_bucket(&env, &stake);
+
+        Ok(())
+    }
+
+    /// Change the spending limit and/or extend the time bound of an active stake
+    /// (staker or operator)
+    pub fn update_stake(
+        env: Env,
+        caller: Address,
+        staker: Address,
+        spending_limit: i128,
+        time_bound: u64,
+    ) -> Result<(), PoolError> {
+        if get_pause_flags(&env).staking {
+            return Err(PoolError::StakingPaused);
+        }
+        require_staker_or_operator(&env, &staker, &caller)?;
+
+        let config = get_config(&env);
+        if spending_limit < config.min_spending_limit || spending_limit > config.max_spending_limit {
+            return Err(PoolError::InvalidSpendingLimit);
+        }
+
+        if let Some(mut stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone())) {
+            if !is_live(&env, &stake) {
+                return Err(PoolError::StakeNotActive);
+            }
+            // Capacity backing live rails cannot be withdrawn
+            if spending_limit < stake.allocated {
+                return Err(PoolError::BelowAllocated);
+            }
+            if time_bound < stake.time_bound {
+                return Err(PoolError::TimeBoundNotExtended);
+            }
+            if time_bound > env.ledger().timestamp() + config.max_time_bound {
+                return Err(PoolError::InvalidTimeBound);
+            }
+            // Operators cannot move the staker's funds, so collateral must already be deposited
+            if stake.collateral < required_collateral(&config, spending_limit) {
+                return Err(PoolError::InsufficientCollateral);
+            }
+            // Re-read the tier so upgraded (or revoked) verification takes effect
+            stake.tier = staker_tier(&env, &staker)?;
+            if spending_limit > tier_limit(&config, stake.tier) {
+                return Err(PoolError::ExceedsTierLimit);
+            }
+
+            resize_stake(&env, &mut stake, spending_limit, time_bound);
+            env.storage().persistent().set(&DataKey::Stake(staker), &stake);
+
+            Ok(())
+        } else {
+            Err(PoolError::StakeNotFound)
+        }
+    }
+
+    /// Unstake identity and withdraw fees
+    /// Capacity is withdrawn immediately; a stake still backing rails waits in the
+    /// unbonding queue until its allocations are released, otherwise collateral is returned.
+    /// While claiming is paused the fees stay on the stake for a later `claim_earnings`
+    pub fn unstake_identity(env: Env, staker: Address) -> Result<i128, PoolError> {
+        staker.require_auth();
+
+        if let Some(mut stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone())) {
+            if stake.unbonding_since.is_some() {
+                return Err(PoolError::UnstakePending);
+            }
+            if stake.frozen {
+                return Err(PoolError::StakeFrozen);
+            }
+
+            let fees = if get_pause_flags(&env).claiming { 0 } else { stake.accumulated_fees };
+            stake.accumulated_fees -= fees;
+            if fees > 0 {
+                pay_fees(&env, &staker, fees)?;
+                record_ledger(&env, &staker, LedgerKind::Claim, None, fees);
+            }
+
+            // An expired stake may already have been swept
+            if stake.is_active {
+                deactivate_stake(&env, &mut stake);
+            }
+
+            if stake.allocated > 0 {
+                stake.unbonding_since = Some(env.ledger().timestamp());
+                set_insert(&env, StakerSet::Unbonding, &staker);
+            } else {
+                return_collateral(&env, &mut stake);
+            }
+            env.storage().persistent().set(&DataKey::Stake(staker), &stake);
+
+            Ok(fees)
+        } else {
+            Err(PoolError::StakeNotFound)
+        }
+    }
+
+    /// Leave the unbonding queue once all allocations have been released
+    pub fn complete_unstake(env: Env, staker: Address) -> Result<(), PoolError> {
+        staker.require_auth();
+
+        if let Some(mut stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone())) {
+            if stake.unbonding_since.is_none() {
+                return Err(PoolError::NotUnbonding);
+            }
+            if stake.frozen {
+                return Err(PoolError::StakeFrozen);
+            }
+            if stake.allocated > 0 {
+                return Err(PoolError::CapacityStillAllocated);
+            }
+
+            stake.unbonding_since = None;
+            set_remove(&env, StakerSet::Unbonding, &staker);
+            return_collateral(&env, &mut stake);
+            env.storage().persistent().set(&DataKey::Stake(staker), &stake);
+            Ok(())
+        } else {
+            Err(PoolError::StakeNotFound)
+        }
+    }
+
+    /// Eject a staker whose SBT is no longer valid (permissionless)
+    /// Pulls the stake's capacity, freezes its earnings, collateral and any shares the
+    /// staker holds pending review, and flags the staker's rails to the Dharma Pool
+    pub fn enforce_identity(env: Env, staker: Address) -> Result<(), PoolError> {
+        let mut stake = env.storage().persistent()
+            .get::<DataKey, Stake>(&DataKey::Stake(staker.clone()))
+            .ok_or(PoolError::StakeNotFound)?;
+        if stake.frozen {
+            return Err(PoolError::AlreadyEjected);
+        }
+
+        let sbt_contract: Address = env.storage().instance().get(&DataKey::SBTContract).unwrap();
+        if SbtClient::new(&env, &sbt_contract).verify_sbt(&staker) {
+            return Err(PoolError::IdentityStillValid);
+        }
+
+        if stake.is_active {
+            deactivate_stake(&env, &mut stake);
+        }
+        // Allocations keep backing their rails until released, as on unstake
+        if stake.allocated > 0 && stake.unbonding_since.is_none() {
+            stake.unbonding_since = Some(env.ledger().timestamp());
+            set_insert(&env, StakerSet::Unbonding, &staker);
+        }
+        stake.frozen = true;
+        env.storage().persistent().set(&DataKey::Stake(staker.clone()), &stake);
+
+        let dharma_pool: Address = env.storage().instance().get(&DataKey::DharmaPool).unwrap();
+        DharmaClient::new(&env, &dharma_pool).flag_staker(&staker);
+
+        env.events().publish((symbol_short!("ejected"), staker), env.ledger().timestamp());
+        Ok(())
+    }
+
+    /// Lift the freeze on an ejected stake after review (admin only); the Dharma Pool
+    /// stops treating its rails as up for re-backing
+    pub fn unfreeze_stake(env: Env, staker: Address) -> Result<(), PoolError> {
+        let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+        admin.require_auth();
+
+        if let Some(mut stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone())) {
+            if !stake.frozen {
+                return Err(PoolError::StakeNotFrozen);
+            }
+            stake.frozen = false;
+            env.storage().persistent().set(&DataKey::Stake(staker.clone()), &stake);
+
+            let dharma_pool: Address = env.storage().instance().get(&DataKey::DharmaPool).unwrap();
+            DharmaClient::new(&env, &dharma_pool).unflag_staker(&staker);
+            Ok(())
+        } else {
+            Err(PoolError::StakeNotFound)
+        }
+    }
+
+    /// Deposit collateral behind an active stake
+    pub fn deposit_collateral(env: Env, staker: Address, amount: i128) -> Result<(), PoolError> {
+        if get_pause_flags(&env).staking {
+            return Err(PoolError::StakingPaused);
+        }
+        staker.require_auth();
+
+        if amount <= 0 {
+            return Err(PoolError::InvalidAmount);
+        }
+
+        let token_address: Address = env.storage().instance()
+            .get(&DataKey::CollateralToken)
+            .ok_or(PoolError::CollateralTokenNotSet)?;
+
+        if let Some(mut stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone())) {
+            if !is_live(&env, &stake) {
+                return Err(PoolError::StakeNotActive);
+            }
+
+            token::Client::new(&env, &token_address)
+                .transfer(&staker, &env.current_contract_address(), &amount);
+
+            if stake.tokenized {
+                vault_deposit(&env, &staker, amount, 0)?;
+            }
+            stake.collateral += amount;
+            env.storage().persistent().set(&DataKey::Stake(staker), &stake);
+            Ok(())
+        } else {
+            Err(PoolError::StakeNotFound)
+        }
+    }
+
+    /// Penalize a staker whose backed agent misbehaved (called by Dharma Pool or the arbiter)
+    /// Collateral goes to the insurance fund, or is burned if none is set. The spending
+    /// limit drops to what the remaining collateral supports, but not below capacity
+    /// backing live rails; collateral missing for that capacity is recorded as a shortfall
+    pub fn slash(
+        env: Env,
+        caller: Address,
+        staker: Address,
+        amount: i128,
+        reason: String,
+    ) -> Result<(), PoolError> {
+        caller.require_auth();
+
+        let dharma_pool: Address = env.storage().instance().get(&DataKey::DharmaPool).unwrap();
+        let arbiter: Option<Address> = env.storage().instance().get(&DataKey::Arbiter);
+        if caller != dharma_pool && Some(caller.clone()) != arbiter {
+            return Err(PoolError::Unauthorized);
+        }
+
+        if amount <= 0 {
+            return Err(PoolError::InvalidAmount);
+        }
+
+        if let Some(mut stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone())) {
+            // Unbonding and expired stakes keep their collateral at risk
+            let slashed = amount.min(stake.collateral);
+            if slashed > 0 {
+                let token_address: Address = env.storage().instance().get(&DataKey::CollateralToken).unwrap();
+                let token = token::Client::new(&env, &token_address);
+                match env.storage().instance().get::<DataKey, Address>(&DataKey::InsuranceFund) {
+                    Some(fund) => token.transfer(&env.current_contract_address(), &fund, &slashed),
+                    None => token.burn(&env.current_contract_address(), &slashed),
+                }
+                stake.collateral -= slashed;
+
+                // Holders of the stake's shares carry the loss of its tokenized collateral
+                if stake.tokenized {
+                    let mut vault = get_vault(&env, &staker);
+                    vault.assets -= slashed;
+                    set_vault(&env, &staker, &vault);
+                }
+            }
+
+            let config = get_config(&env);
+            let mut capacity_reduction = 0;
+            let mut collateral_shortfall = 0;
+            if stake.is_active && config.collateral_ratio_bps > 0 {
+                let supported = stake.collateral * BPS_DENOMINATOR / config.collateral_ratio_bps as i128;
+                if stake.spending_limit > supported {
+                    let spending_limit = supported.max(stake.allocated);
+                    capacity_reduction = stake.spending_limit - spending_limit;
+                    collateral_shortfall = (required_collateral(&config, spending_limit) - stake.collateral).max(0);
+                    let time_bound = stake.time_bound;
+                    resize_stake(&env, &mut stake, spending_limit, time_bound);
+                }
+            }
+            stake.total_slashed += slashed;
+
+            let count: u32 = env.storage().persistent().get(&DataKey::SlashCount(staker.clone())).unwrap_or(0);
+            let record = SlashRecord {
+                amount: slashed,
+                capacity_reduction,
+                collateral_shortfall,
+                reason,
+                slashed_by: caller,
+                slashed_at: env.ledger().timestamp(),
+            };
+            env.storage().persistent().set(&DataKey::Slash(staker.clone(), count), &record);
+            env.storage().persistent().set(&DataKey::SlashCount(staker.clone()), &(count + 1));
+
+            env.storage().persistent().set(&DataKey::Stake(staker), &stake);
+            Ok(())
+        } else {
+            Err(PoolError::StakeNotFound)
+        }
+    }
+
+    /// Get a page of a staker's slash records (at most 100 per call)
+    pub fn get_slash_history(env: Env, staker: Address, offset: u32, limit: u32) -> Vec<SlashRecord> {
+        let count: u32 = env.storage().persistent().get(&DataKey::SlashCount(staker.clone())).unwrap_or(0);
+        let end = offset.saturating_add(limit.min(MAX_PAGE_SIZE)).min(count);
+
+        let mut records = Vec::new(&env);
+        for index in offset..end {
+            if let Some(record) = env.storage().persistent().get::<DataKey, SlashRecord>(&DataKey::Slash(staker.clone(), index)) {
+                records.push_back(record);
+            }
+        }
+        records
+    }
+
+    /// Replace the staking config (admin only)
+    pub fn set_config(env: Env, config: StakingConfig) -> Result<(), PoolError> {
+        let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+        admin.require_auth();
+
+        validate_config(&config)?;
+        env.storage().instance().set(&DataKey::Config, &config);
+        env.events().publish((symbol_short!("config"),), config);
+        Ok(())
+    }
+
+    /// Get the staking config
+    pub fn get_config(env: Env) -> StakingConfig {
+        get_config(&env)
+    }
+
+    /// Set the collateral token (admin only, once)
+    pub fn set_collateral_token(env: Env, token: Address) -> Result<(), PoolError> {
+        let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+        admin.require_auth();
+
+        if env.storage().instance().has(&DataKey::CollateralToken) {
+            return Err(PoolError::CollateralTokenAlreadySet);
+        }
+        env.storage().instance().set(&DataKey::CollateralToken, &token);
+        Ok(())
+    }
+
+    /// Get the collateral token, which fees must also be paid in
+    pub fn get_collateral_token(env: Env) -> Option<Address> {
+        env.storage().instance().get(&DataKey::CollateralToken)
+    }
+
+    /// Set or clear the insurance fund receiving slashed collateral (admin only)
+    pub fn set_insurance_fund(env: Env, fund: Option<Address>) {
+        let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+        admin.require_auth();
+
+        match fund {
+            Some(fund) => env.storage().instance().set(&DataKey::InsuranceFund, &fund),
+            None => env.storage().instance().remove(&DataKey::InsuranceFund),
+        }
+    }
+
+    /// Set or clear the arbiter allowed to slash (admin only)
+    pub fn set_arbiter(env: Env, arbiter: Option<Address>) {
+        let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+        admin.require_auth();
+
+        match arbiter {
+            Some(arbiter) => env.storage().instance().set(&DataKey::Arbiter, &arbiter),
+            None => env.storage().instance().remove(&DataKey::Arbiter),
+        }
+    }
+
+    /// Set or clear the pauser (admin only)
+    pub fn set_pauser(env: Env, pauser: Option<Address>) {
+        let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+        admin.require_auth();
+
+        match pauser {
+            Some(pauser) => env.storage().instance().set(&DataKey::Pauser, &pauser),
+            None => env.storage().instance().remove(&DataKey::Pauser),
+        }
+    }
+
+    /// Set the pause flags; the pauser may only pause, the admin may also unpause
+    /// Unstaking and reads stay available while paused
+    pub fn set_paused(env: Env, caller: Address, flags: PauseFlags) -> Result<(), PoolError> {
+        caller.require_auth();
+
+        let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+        if caller != admin {
+            let pauser: Option<Address> = env.storage().instance().get(&DataKey::Pauser);
+            if Some(caller) != pauser {
+                return Err(PoolError::Unauthorized);
+            }
+            let current = get_pause_flags(&env);
+            if (current.staking && !flags.staking)
+                || (current.claiming && !flags.claiming)
+                || (current.fee_intake && !flags.fee_intake)
+            {
+                return Err(PoolError::OnlyAdminCanUnpause);
+            }
+        }
+
+        env.storage().instance().set(&DataKey::Paused, &flags);
+        env.events().publish((symbol_short!("paused"),), flags);
+        Ok(())
+    }
+
+    pub fn get_paused(env: Env) -> PauseFlags {
+        get_pause_flags(&env)
+    }
+
+    /// Get free (unallocated) capacity in the pool
+    /// Stakes stop counting from the start of the epoch in which they expire. A single
+    /// allocation can only draw on the MAX_ALLOCATION_SCAN stakes it reads
+    pub fn get_available_capacity(env: Env) -> i128 {
+        let total_capacity: i128 = env.storage().instance().get(&DataKey::TotalCapacity).unwrap_or(0);
+        let total_allocated: i128 = env.storage().instance().get(&DataKey::TotalAllocated).unwrap_or(0);
+        let (expired_capacity, expired_allocated) = expired_totals(&env);
+        (total_capacity - expired_capacity) - (total_allocated - expired_allocated)
+    }
+
+    /// Finalize up to `limit` stakes that expired in past epochs (permissionless)
+    pub fn sweep_expired(env: Env, limit: u32) -> u32 {
+        let current_epoch = env.ledger().timestamp() / EPOCH_LENGTH;
+        let epochs: Vec<u64> = env.storage().persistent()
+            .get(&DataKey::ExpiryEpochs)
+            .unwrap_or(Vec::new(&env));
+        let mut swept = 0u32;
+
+        for epoch in epochs.iter() {
+            if epoch >= current_epoch || swept == limit {
+                break;
+            }
+            let bucket: ExpiryBucket = env.storage().persistent().get(&DataKey::ExpiryBucket(epoch)).unwrap();
+
+            // Walk from the end so swap-removal never moves an unvisited slot
+            let mut slot = bucket.count;
+            while slot > 0 && swept < limit {
+                slot -= 1;
+                let staker: Address = env.storage().persistent().get(&DataKey::ExpiryAt(epoch, slot)).unwrap();
+                let mut stake: Stake = env.storage().persistent().get(&DataKey::Stake(staker.clone())).unwrap();
+                deactivate_stake(&env, &mut stake);
+                env.storage().persistent().set(&DataKey::Stake(staker), &stake);
+                swept += 1;
+            }
+        }
+
+        swept
+    }
+
+    /// Get capacity currently backing live rails
+    pub fn get_total_allocated(env: Env) -> i128 {
+        env.storage().instance().get(&DataKey::TotalAllocated).unwrap_or(0)
+    }
+
+    /// Reserve part of a staker's free capacity for an agent's rail (called by Dharma Pool)
+    pub fn reserve_capacity(
+        env: Env,
+        staker: Address,
+        agent: Address,
+        amount: i128,
+        duration: u64,
+    ) -> Result<(), PoolError> {
+        let dharma_pool: Address = env.storage().instance().get(&DataKey::DharmaPool).unwrap();
+        dharma_pool.require_auth();
+
+        if amount <= 0 {
+            return Err(PoolError::InvalidAmount);
+        }
+
+        if let Some(mut stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone())) {
+            if !is_live(&env, &stake) {
+                return Err(PoolError::StakeNotActive);
+            }
+            if stake.allocated + amount > stake.spending_limit {
+                return Err(PoolError::InsufficientCapacity);
+            }
+            if let Some(policy) = env.storage().persistent().get::<DataKey, StakePolicy>(&DataKey::Policy(staker.clone())) {
+                check_policy(&env, &policy, &staker, &agent_profile(&env, &agent), amount, duration)?;
+            }
+
+            reserve_stake(&env, &mut stake, &agent, amount);
+
+            let total_allocated: i128 = env.storage().instance().get(&DataKey::TotalAllocated).unwrap_or(0);
+            env.storage().instance().set(&DataKey::TotalAllocated, &(total_allocated + amount));
+            Ok(())
+        } else {
+            Err(PoolError::StakeNotFound)
+        }
+    }
+
+    /// Release capacity previously reserved for an agent (called by Dharma Pool)
+    pub fn release_capacity(env: Env, staker: Address, agent: Address, amount: i128) -> Result<(), PoolError> {
+        let dharma_pool: Address = env.storage().instance().get(&DataKey::DharmaPool).unwrap();
+        dharma_pool.require_auth();
+
+        release_stake(&env, &staker, &agent, amount)
+    }
+
+    /// Pick backers for a rail and reserve their capacity (called by Dharma Pool)
+    pub fn allocate(env: Env, amount: i128, agent: Address, duration: u64) -> Result<Vec<Backing>, PoolError> {
+        let dharma_pool: Address = env.storage().instance().get(&DataKey::DharmaPool).unwrap();
+        dharma_pool.require_auth();
+
+        allocate_backers(&env, amount, &agent, duration, None)
+    }
+
+    /// Move one staker's backing of an agent onto other stakes (called by Dharma Pool)
+    pub fn reallocate(
+        env: Env,
+        agent: Address,
+        from: Address,
+        amount: i128,
+        duration: u64,
+    ) -> Result<Vec<Backing>, PoolError> {
+        let dharma_pool: Address = env.storage().instance().get(&DataKey::DharmaPool).unwrap();
+        dharma_pool.require_auth();
+
+        let backings = allocate_backers(&env, amount, &agent, duration, Some(&from))?;
+        release_stake(&env, &from, &agent, amount)?;
+        Ok(backings)
+    }
+
+    /// Release every backing of an allocation (called by Dharma Pool)
+    pub fn release_allocation(env: Env, agent: Address, backings: Vec<Backing>) -> Result<(), PoolError> {
+        let dharma_pool: Address = env.storage().instance().get(&DataKey::DharmaPool).unwrap();
+        dharma_pool.require_auth();
+
+        for backing in backings.iter() {
+            release_stake(&env, &backing.staker, &agent, backing.amount)?;
+        }
+        Ok(())
+    }
+
+    /// Set which agents this stake may back
+    pub fn set_stake_policy(env: Env, staker: Address, policy: StakePolicy) -> Result<(), PoolError> {
+        staker.require_auth();
+
+        if !env.storage().persistent().has(&DataKey::Stake(staker.clone())) {
+            return Err(PoolError::StakeNotFound);
+        }
+        if policy.allowed.len() > MAX_POLICY_LIST || policy.denied.len() > MAX_POLICY_LIST {
+            return Err(PoolError::PolicyListTooLong);
+        }
+        if policy.max_exposure_per_agent < 0 {
+            return Err(PoolError::InvalidExposureLimit);
+        }
+
+        env.storage().persistent().set(&DataKey::Policy(staker), &policy);
+        Ok(())
+    }
+
+    /// Remove a stake's policy so it backs any agent
+    pub fn clear_stake_policy(env: Env, staker: Address) {
+        staker.require_auth();
+        env.storage().persistent().remove(&DataKey::Policy(staker));
+    }
+
+    /// Get a stake's policy, if any
+    pub fn get_stake_policy(env: Env, staker: Address) -> Option<StakePolicy> {
+        env.storage().persistent().get(&DataKey::Policy(staker))
+    }
+
+    /// Get capacity a staker has reserved for an agent
+    pub fn get_exposure(env: Env, staker: Address, agent: Address) -> i128 {
+        env.storage().persistent().get(&DataKey::Exposure(staker, agent)).unwrap_or(0)
+    }
+
+    /// Authorize an operator to update limits, extend time bounds and claim earnings
+    /// on the staker's behalf; earnings are always paid to the staker
+    pub fn set_operator(env: Env, staker: Address, operator: Address) -> Result<(), PoolError> {
+        staker.require_auth();
+
+        if operator == staker {
+            return Err(PoolError::InvalidOperator);
+        }
+        env.storage().persistent().set(&DataKey::Operator(staker), &operator);
+        Ok(())
+    }
+
+    /// Revoke the staker's operator
+    pub fn revoke_operator(env: Env, staker: Address) {
+        staker.require_auth();
+        env.storage().persistent().remove(&DataKey::Operator(staker));
+    }
+
+    /// Get the staker's operator, if any
+    pub fn get_operator(env: Env, staker: Address) -> Option<Address> {
+        env.storage().persistent().get(&DataKey::Operator(staker))
+    }
+
+    /// Get stake details
+    pub fn get_stake(env: Env, staker: Address) -> Option<Stake> {
+        env.storage().persistent().get(&DataKey::Stake(staker))
+    }
+
+    /// Get a page of a staker's archived stake periods (at most 100 per call)
+    pub fn get_stake_history(env: Env, staker: Address, offset: u32, limit: u32) -> Vec<StakePeriod> {
+        let count: u32 = env.storage().persistent().get(&DataKey::HistoryCount(staker.clone())).unwrap_or(0);
+        let end = offset.saturating_add(limit.min(MAX_PAGE_SIZE)).min(count);
+
+        let mut periods = Vec::new(&env);
+        for index in offset..end {
+            if let Some(period) = env.storage().persistent().get::<DataKey, StakePeriod>(&DataKey::History(staker.clone(), index)) {
+                periods.push_back(period);
+            }
+        }
+        periods
+    }
+
+    /// Get the number of archived stake periods for a staker
+    pub fn get_stake_history_count(env: Env, staker: Address) -> u32 {
+        env.storage().persistent().get(&DataKey::HistoryCount(staker)).unwrap_or(0)
+    }
+
+    /// Page through a staker's earnings ledger, oldest first
+    pub fn get_earnings_ledger(env: Env, staker: Address, offset: u32, limit: u32) -> Vec<LedgerEntry> {
+        let count: u32 = env.storage().persistent().get(&DataKey::LedgerCount(staker.clone())).unwrap_or(0);
+        let end = offset.saturating_add(limit.min(MAX_PAGE_SIZE)).min(count);
+
+        let mut entries = Vec::new(&env);
+        for index in offset..end {
+            entries.push_back(env.storage().persistent().get(&DataKey::Ledger(staker.clone(), index)).unwrap());
+        }
+        entries
+    }
+
+    pub fn get_earnings_ledger_count(env: Env, staker: Address) -> u32 {
+        env.storage().persistent().get(&DataKey::LedgerCount(staker)).unwrap_or(0)
+    }
+
+    /// Credits, claims, compounding and vault deposits with `from <= timestamp < to`
+    pub fn get_earnings_totals(env: Env, staker: Address, from: u64, to: u64) -> EarningsTotals {
+        let count: u32 = env.storage().persistent().get(&DataKey::LedgerCount(staker.clone())).unwrap_or(0);
+        let before = ledger_totals_before(&env, &staker, count, from);
+        let until = ledger_totals_before(&env, &staker, count, to.max(from));
+        EarningsTotals {
+            credited: until.credited - before.credited,
+            claimed: until.claimed - before.claimed,
+            compounded: until.compounded - before.compounded,
+            vaulted: until.vaulted - before.vaulted,
+        }
+    }
+
+    /// Trailing 30-day annualized yield in basis points
+    pub fn calculate_apy(env: Env) -> u32 {
+        trailing_yield_bps(&env, APY_LONG_WINDOW)
+    }
+
+    /// Trailing 7-day annualized yield in basis points
+    pub fn calculate_apy_7d(env: Env) -> u32 {
+        trailing_yield_bps(&env, APY_SHORT_WINDOW)
+    }
+
+    /// Get the fee and capacity checkpoint for an epoch (day index)
+    pub fn get_epoch(env: Env, epoch: u64) -> Option<EpochCheckpoint> {
+        env.storage().persistent().get(&DataKey::Epoch(epoch))
+    }
+
+    /// Annualized yield a staker has realized since staking, in basis points
+    pub fn get_staker_yield(env: Env, staker: Address) -> u32 {
+        if let Some(stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker)) {
+            let elapsed = env.ledger().timestamp().saturating_sub(stake.staked_at) as i128;
+            if elapsed == 0 || stake.spending_limit <= 0 {
+                return 0;
+            }
+            let bps = stake.total_fees_earned * BPS_DENOMINATOR * SECONDS_PER_YEAR
+                / (stake.spending_limit * elapsed);
+            bps.clamp(0, u32::MAX as i128) as u32
+        } else {
+            0
+        }
+    }
+
+    /// Opt in or out of auto-compounding (staker or operator)
+    pub fn set_auto_compound(env: Env, caller: Address, staker: Address, enabled: bool) -> Result<(), PoolError> {
+        require_staker_or_operator(&env, &staker, &caller)?;
+
+        if let Some(mut stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone())) {
+            stake.auto_compound = enabled;
+            env.storage().persistent().set(&DataKey::Stake(staker), &stake);
+            Ok(())
+        } else {
+            Err(PoolError::StakeNotFound)
+        }
+    }
+
+    /// Convert an opted-in staker's accrued fees into collateral and spending limit,
+    /// up to the maximum limit (permissionless, at most once per interval)
+    /// Returns the fees converted; any fees beyond the cap stay claimable
+    pub fn compound_earnings(env: Env, staker: Address) -> Result<i128, PoolError> {
+        if get_pause_flags(&env).claiming {
+            return Err(PoolError::ClaimingPaused);
+        }
+        if let Some(mut stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone())) {
+            if !stake.auto_compound {
+                return Err(PoolError::AutoCompoundDisabled);
+            }
+            if stake.frozen {
+                return Err(PoolError::StakeFrozen);
+            }
+            if !is_live(&env, &stake) {
+                return Err(PoolError::StakeNotActive);
+            }
+            let now = env.ledger().timestamp();
+            if now < stake.last_compounded_at + COMPOUND_INTERVAL {
+                return Err(PoolError::CompoundedTooRecently);
+            }
+
+            let config = get_config(&env);
+            let headroom = (tier_limit(&config, stake.tier) - stake.spending_limit).max(0);
+
+            // Each unit of fees backs 1 / collateral ratio of spending limit (1:1 without a ratio)
+            let ratio = config.collateral_ratio_bps as i128;
+            let (converted, limit_increase) = if ratio == 0 {
+                let converted = stake.accumulated_fees.min(headroom);
+                (converted, converted)
+            } else {
+                let limit_increase = (stake.accumulated_fees * BPS_DENOMINATOR / ratio).min(headroom);
+                (required_collateral(&config, limit_increase), limit_increase)
+            };
+
+            stake.accumulated_fees -= converted;
+            stake.collateral += converted;
+            stake.last_compounded_at = now;
+            if converted > 0 {
+                record_ledger(&env, &staker, LedgerKind::Compound, None, converted);
+            }
+            if limit_increase > 0 {
+                let (spending_limit, time_bound) = (stake.spending_limit + limit_increase, stake.time_bound);
+                resize_stake(&env, &mut stake, spending_limit, time_bound);
+            }
+
+            env.storage().persistent().set(&DataKey::Stake(staker), &stake);
+            Ok(converted)
+        } else {
+            Err(PoolError::StakeNotFound)
+        }
+    }
+
+    /// Move a stake's collateral and unclaimed fees into its share vault, minting
+    /// transferable shares to the staker; the stake itself stays with the staker.
+    /// From then on the stake's fees and slashes land on its shares
+    pub fn tokenize_stake(env: Env, staker: Address) -> Result<i128, PoolError> {
+        if get_pause_flags(&env).staking {
+            return Err(PoolError::StakingPaused);
+        }
+        staker.require_auth();
+
+        if let Some(mut stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone())) {
+            if !is_live(&env, &stake) {
+                return Err(PoolError::StakeNotActive);
+            }
+            if stake.tokenized {
+                return Err(PoolError::AlreadyTokenized);
+            }
+            let fees = stake.accumulated_fees;
+            if stake.collateral + fees <= 0 {
+                return Err(PoolError::NothingToTokenize);
+            }
+
+            let shares = vault_deposit(&env, &staker, stake.collateral + fees, fees)?;
+            if fees > 0 {
+                record_ledger(&env, &staker, LedgerKind::Tokenized, None, fees);
+            }
+            stake.accumulated_fees = 0;
+            stake.tokenized = true;
+            env.storage().persistent().set(&DataKey::Stake(staker), &stake);
+            Ok(shares)
+        } else {
+            Err(PoolError::StakeNotFound)
+        }
+    }
+
+    /// Burn shares of a stake for their value in the collateral token, up to the
+    /// liquid assets of the stake's vault
+    pub fn redeem(env: Env, holder: Address, stake: Address, shares: i128) -> Result<i128, PoolError> {
+        if get_pause_flags(&env).claiming {
+            return Err(PoolError::ClaimingPaused);
+        }
+        holder.require_auth();
+
+        if shares_frozen(&env, &holder) {
+            return Err(PoolError::StakeFrozen);
+        }
+        let vault = get_vault(&env, &stake);
+        if shares <= 0 || shares > share_balance(&env, &stake, &holder) {
+            return Err(PoolError::InvalidAmount);
+        }
+        let amount = shares * vault.assets / vault.shares;
+        if amount > vault.liquid {
+            return Err(PoolError::InsufficientLiquidity);
+        }
+
+        burn_shares(&env, &stake, &holder, shares);
+        let mut vault = get_vault(&env, &stake);
+        vault.assets -= amount;
+        vault.liquid -= amount;
+        set_vault(&env, &stake, &vault);
+
+        let token_address: Address = env.storage().instance().get(&DataKey::CollateralToken).unwrap();
+        token::Client::new(&env, &token_address).transfer(&env.current_contract_address(), &holder, &amount);
+        Ok(amount)
+    }
+
+    pub fn get_vault(env: Env, stake: Address) -> ShareVault {
+        get_vault(&env, &stake)
+    }
+
+    /// Share balance of `id` in a stake's share class. The share functions follow
+    /// SEP-41, with the stake naming the share class
+    pub fn share_balance(env: Env, stake: Address, id: Address) -> i128 {
+        share_balance(&env, &stake, &id)
+    }
+
+    pub fn share_allowance(env: Env, stake: Address, from: Address, spender: Address) -> i128 {
+        match env.storage().persistent().get::<DataKey, ShareAllowance>(&DataKey::ShareAllowance(stake, from, spender)) {
+            Some(allowance) if allowance.expiration_ledger >= env.ledger().sequence() => allowance.amount,
+            _ => 0,
+        }
+    }
+
+    pub fn approve_shares(env: Env, stake: Address, from: Address, spender: Address, amount: i128, expiration_ledger: u32) {
+        from.require_auth();
+
+        if amount < 0 || (amount > 0 && expiration_ledger < env.ledger().sequence()) {
+            panic!("invalid allowance");
+        }
+        env.storage().persistent().set(
+            &DataKey::ShareAllowance(stake.clone(), from.clone(), spender.clone()),
+            &ShareAllowance { amount, expiration_ledger },
+        );
+        env.events().publish((symbol_short!("approve"), stake, from, spender), (amount, expiration_ledger));
+    }
+
+    pub fn transfer_shares(env: Env, stake: Address, from: Address, to: Address, amount: i128) {
+        from.require_auth();
+        move_shares(&env, &stake, &from, &to, amount);
+    }
+
+    pub fn transfer_shares_from(env: Env, stake: Address, spender: Address, from: Address, to: Address, amount: i128) {
+        spender.require_auth();
+        spend_share_allowance(&env, &stake, &from, &spender, amount);
+        move_shares(&env, &stake, &from, &to, amount);
+    }
+
+    pub fn burn_shares(env: Env, stake: Address, from: Address, amount: i128) {
+        from.require_auth();
+        burn_shares(&env, &stake, &from, amount);
+    }
+
+    pub fn burn_shares_from(env: Env, stake: Address, spender: Address, from: Address, amount: i128) {
+        spender.require_auth();
+        spend_share_allowance(&env, &stake, &from, &spender, amount);
+        burn_shares(&env, &stake, &from, amount);
+    }
+
+    /// Claim accumulated earnings to the staker (staker or operator)
+    pub fn claim_earnings(env: Env, caller: Address, staker: Address) -> Result<i128, PoolError> {
+        if get_pause_flags(&env).claiming {
+            return Err(PoolError::ClaimingPaused);
+        }
+        require_staker_or_operator(&env, &staker, &caller)?;
+
+        if let Some(mut stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker.clone())) {
+            if stake.frozen {
+                return Err(PoolError::StakeFrozen);
+            }
+            let fees = stake.accumulated_fees;
+            stake.accumulated_fees = 0;
+            if fees > 0 {
+                pay_fees(&env, &staker, fees)?;
+                record_ledger(&env, &staker, LedgerKind::Claim, None, fees);
+            }
+            env.storage().persistent().set(&DataKey::Stake(staker), &stake);
+            Ok(fees)
+        } else {
+            Err(PoolError::StakeNotFound)
+        }
+    }
+
+    /// Add fees to a staker (called by Dharma Pool)
+    pub fn add_fees(env: Env, staker: Address, amount: i128, rail_id: BytesN<32>) -> Result<(), PoolError> {
+        let dharma_pool: Address = env.storage().instance().get(&DataKey::DharmaPool).unwrap();
+        dharma_pool.require_auth();
+
+        if get_pause_flags(&env).fee_intake {
+            return Err(PoolError::FeeIntakePaused);
+        }
+
+        credit_fees(&env, &staker, amount, &rail_id)
+    }
+
+    /// Split a rail's staker fees across its backers by backed amount (called by Dharma Pool,
+    /// which pays `amount` in to this contract in the same transaction once this succeeds).
+    /// Backers that can no longer earn, such as those that unstaked while the fees were
+    /// held, are skipped and their part goes to the rest
+    pub fn distribute_fees(env: Env, rail_id: BytesN<32>, amount: i128, backings: Vec<Backing>) -> Result<(), PoolError> {
+        let dharma_pool: Address = env.storage().instance().get(&DataKey::DharmaPool).unwrap();
+        dharma_pool.require_auth();
+
+        if get_pause_flags(&env).fee_intake {
+            return Err(PoolError::FeeIntakePaused);
+        }
+        if amount <= 0 {
+            return Err(PoolError::InvalidAmount);
+        }
+
+        let mut live: Vec<Backing> = Vec::new(&env);
+        let mut total_backed: i128 = 0;
+        let mut largest: u32 = 0;
+        for backing in backings.iter() {
+            if backing.amount <= 0 {
+                return Err(PoolError::InvalidBacking);
+            }
+            let stake: Option<Stake> = env.storage().persistent().get(&DataKey::Stake(backing.staker.clone()));
+            if !stake.is_some_and(|stake| is_live(&env, &stake)) {
+                continue;
+            }
+            if !live.is_empty() && backing.amount > live.get(largest).unwrap().amount {
+                largest = live.len();
+            }
+            total_backed += backing.amount;
+            live.push_back(backing);
+        }
+        if total_backed == 0 {
+            return Err(PoolError::NoBackers);
+        }
+
+        let mut shares: Vec<i128> = Vec::new(&env);
+        let mut distributed: i128 = 0;
+        for backing in live.iter() {
+            let share = amount * backing.amount / total_backed;
+            shares.push_back(share);
+            distributed += share;
+        }
+        // Rounding dust goes to the largest backer, the earliest one on ties
+        shares.set(largest, shares.get(largest).unwrap() + amount - distributed);
+
+        for (backing, share) in live.iter().zip(shares.iter()) {
+            if share > 0 {
+                credit_fees(&env, &backing.staker, share, &rail_id)?;
+            }
+        }
+
+        env.events().publish((symbol_short!("fees"), rail_id), amount);
+        Ok(())
+    }
+
+    /// Get a page of active stakers (at most 100 per call)
+    pub fn get_active_stakers(env: Env, offset: u32, limit: u32) -> Vec<Address> {
+        set_page(&env, StakerSet::Active, offset, limit)
+    }
+
+    /// Get the number of active stakers
+    pub fn get_active_staker_count(env: Env) -> u32 {
+        set_len(&env, StakerSet::Active)
+    }
+
+    /// Get a page of stakers waiting for their allocations to clear (at most 100 per call)
+    pub fn get_unbonding_queue(env: Env, offset: u32, limit: u32) -> Vec<Address> {
+        set_page(&env, StakerSet::Unbonding, offset, limit)
+    }
+
+    /// Get the number of stakers in the unbonding queue
+    pub fn get_unbonding_count(env: Env) -> u32 {
+        set_len(&env, StakerSet::Unbonding)
+    }
+
+    /// Check if staker is active
+    pub fn is_active(env: Env, staker: Address) -> bool {
+        if let Some(stake) = env.storage().persistent().get::<DataKey, Stake>(&DataKey::Stake(staker)) {
+            is_live(&env, &stake)
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use soroban_sdk::{
+        testutils::{Address as _, Events, Ledger},
+        token::{StellarAssetClient, TokenClient},
+        vec, Env, IntoVal,
+    };
+
+    #[contracttype]
+    enum MockKey {
+        Level(Address),
+        Principal(Address),
+    }
+
+    /// Stands in for the SBT contract
+    #[contract]
+    pub struct MockSbt;
+
+    #[contractimpl]
+    impl MockSbt {
+        pub fn set_level(env: Env, owner: Address, level: u32) {
+            env.storage().persistent().set(&MockKey::Level(owner), &level);
+        }
+
+        pub fn set_principal(env: Env, agent: Address, principal: Address) {
+            env.storage().persistent().set(&MockKey::Principal(agent), &principal);
+        }
+
+        pub fn verify_sbt(env: Env, owner: Address) -> bool {
+            Self::get_assurance_level(env, owner) > 0
+        }
+
+        pub fn get_assurance_level(env: Env, owner: Address) -> u32 {
+            env.storage().persistent().get(&MockKey::Level(owner)).unwrap_or(0)
+        }
+
+        pub fn get_principal(env: Env, agent: Address) -> Option<Address> {
+            env.storage().persistent().get(&MockKey::Principal(agent))
+        }
+    }
+
+    #[contracttype]
+    enum MockDharmaKey {
+        Flagged(Address),
+    }
+
+    /// Stands in for the Dharma Pool's callbacks
+    #[contract]
+    pub struct MockDharma;
+
+    #[contractimpl]
+    impl MockDharma {
+        pub fn flag_staker(env: Env, staker: Address) {
+            env.storage().persistent().set(&MockDharmaKey::Flagged(staker), &true);
+        }
+
+        pub fn unflag_staker(env: Env, staker: Address) {
+            env.storage().persistent().remove(&MockDharmaKey::Flagged(staker));
+        }
+
+        pub fn is_flagged(env: Env, staker: Address) -> bool {
+            env.storage().persistent().has(&MockDharmaKey::Flagged(staker))
+        }
+    }
+
+    /// Set the collateral token and pay `fees` into the pool, as the Dharma Pool does
+    /// before crediting them
+    fn fund_fees<'a>(env: &Env, client: &IdentityPoolContractClient, admin: &Address, fees: i128) -> TokenClient<'a> {
+        let token_address = env.register_stellar_asset_contract_v2(admin.clone()).address();
+        StellarAssetClient::new(env, &token_address).mint(&client.address, &fees);
+        client.set_collateral_token(&token_address);
+        TokenClient::new(env, &token_address)
+    }
+
+    #[test]
+    fn test_stake_and_unstake() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let staker = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+
+        env.mock_all_auths();
+        
+        // Stake
+        let result = client.try_stake_identity(&staker, &10_000_000, &(env.ledger().timestamp() + 86400));
+        assert!(result.is_ok());
+
+        // Check capacity
+        assert_eq!(client.get_available_capacity(), 10_000_000);
+
+        // Check stake
+        let stake = client.get_stake(&staker).unwrap();
+        assert_eq!(stake.spending_limit, 10_000_000);
+        assert!(stake.is_active);
+
+        // Unstake
+        let fees = client.unstake_identity(&staker);
+        assert_eq!(fees, 0);
+        assert_eq!(client.get_available_capacity(), 0);
+    }
+
+    #[test]
+    fn test_fee_accumulation() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let staker = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+
+        env.mock_all_auths();
+        let token = fund_fees(&env, &client, &admin, 1_000_000);
+        client.stake_identity(&staker, &10_000_000, &(env.ledger().timestamp() + 86400));
+
+        // Add fees
+        client.add_fees(&staker, &1_000_000, &BytesN::from_array(&env, &[0; 32]));
+
+        let stake = client.get_stake(&staker).unwrap();
+        assert_eq!(stake.accumulated_fees, 1_000_000);
+
+        // Claim fees
+        let claimed = client.claim_earnings(&staker, &staker);
+        assert_eq!(claimed, 1_000_000);
+        assert_eq!(token.balance(&staker), 1_000_000);
+
+        let stake = client.get_stake(&staker).unwrap();
+        assert_eq!(stake.accumulated_fees, 0);
+    }
+
+    #[test]
+    fn test_apy_from_fee_history() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let staker = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+
+        env.mock_all_auths();
+        env.ledger().set_timestamp(100 * EPOCH_LENGTH);
+        client.stake_identity(&staker, &10_000_000, &(200 * EPOCH_LENGTH));
+        client.add_fees(&staker, &70_000, &BytesN::from_array(&env, &[0; 32]));
+
+        // Six days later the pool has existed for seven epochs
+        env.ledger().set_timestamp(106 * EPOCH_LENGTH);
+
+        // 70,000 over 7 epochs of 10,000,000 capacity = 36.5% annualized
+        assert_eq!(client.calculate_apy_7d(), 3650);
+        // Epochs before the pool had capacity do not dilute the 30-day figure
+        assert_eq!(client.calculate_apy(), 3650);
+
+        // Realized over the 6 days since staking
+        assert_eq!(client.get_staker_yield(&staker), 4258);
+    }
+
+    #[test]
+    fn test_staker_registry_scales() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+
+        env.mock_all_auths();
+        let time_bound = env.ledger().timestamp() + 86400;
+
+        // Setup is not what we are metering; the registry is filled directly since
+        // 3,000 stake invocations take minutes under the test host
+        env.cost_estimate().budget().reset_unlimited();
+        let first = Address::generate(&env);
+        sbt.set_level(&first, &1);
+        client.stake_identity(&first, &1_000_000, &time_bound);
+        env.as_contract(&contract_id, || {
+            for _ in 0..3_000 {
+                set_insert(&env, StakerSet::Active, &Address::generate(&env));
+            }
+        });
+
+        // Staking and unstaking must stay within the default budget regardless of pool size
+        let last = Address::generate(&env);
+        sbt.set_level(&last, &1);
+        env.cost_estimate().budget().reset_default();
+        client.stake_identity(&last, &1_000_000, &time_bound);
+        env.cost_estimate().budget().reset_default();
+        client.unstake_identity(&first);
+
+        assert_eq!(client.get_active_staker_count(), 3_001);
+
+        // The last staker was swapped into the vacated slot
+        let page = client.get_active_stakers(&0, &1);
+        assert_eq!(page.get(0).unwrap(), last);
+
+        // Pages are capped
+        assert_eq!(client.get_active_stakers(&0, &1_000).len(), 100);
+        assert_eq!(client.get_active_stakers(&3_000, &100).len(), 1);
+    }
+
+    #[test]
+    fn test_capacity_reservation() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let agent = Address::generate(&env);
+        let staker = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+
+        env.mock_all_auths();
+        client.stake_identity(&staker, &10_000_000, &(env.ledger().timestamp() + 86400));
+
+        // Reserve part of the stake
+        client.reserve_capacity(&staker, &agent, &4_000_000, &3600);
+        assert_eq!(client.get_available_capacity(), 6_000_000);
+        assert_eq!(client.get_total_allocated(), 4_000_000);
+        assert_eq!(client.get_stake(&staker).unwrap().allocated, 4_000_000);
+
+        // Cannot over-commit the stake
+        let result = client.try_reserve_capacity(&staker, &agent, &7_000_000, &3600);
+        assert!(result.is_err());
+
+        // Release
+        client.release_capacity(&staker, &agent, &4_000_000);
+        assert_eq!(client.get_available_capacity(), 10_000_000);
+        assert_eq!(client.get_total_allocated(), 0);
+    }
+
+    #[test]
+    fn test_expired_stakes_excluded() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let agent = Address::generate(&env);
+        let short_staker = Address::generate(&env);
+        let long_staker = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&short_staker, &2);
+        sbt.set_level(&long_staker, &2);
+
+        env.mock_all_auths();
+        env.ledger().set_timestamp(100 * EPOCH_LENGTH);
+        client.stake_identity(&short_staker, &10_000_000, &(101 * EPOCH_LENGTH + 3600));
+        client.stake_identity(&long_staker, &20_000_000, &(110 * EPOCH_LENGTH));
+        client.reserve_capacity(&short_staker, &agent, &1_000_000, &3600);
+        assert_eq!(client.get_available_capacity(), 29_000_000);
+
+        // No longer offered from the start of its expiry epoch, though it can still back rails
+        env.ledger().set_timestamp(101 * EPOCH_LENGTH);
+        assert_eq!(client.get_available_capacity(), 20_000_000);
+        assert!(client.is_active(&short_staker));
+
+        // Expired
+        env.ledger().set_timestamp(101 * EPOCH_LENGTH + 3600);
+        assert!(!client.is_active(&short_staker));
+        assert!(client.try_add_fees(&short_staker, &1_000, &BytesN::from_array(&env, &[0; 32])).is_err());
+        assert!(client.try_reserve_capacity(&short_staker, &agent, &1_000_000, &3600).is_err());
+
+        // Only past epochs are swept
+        assert_eq!(client.sweep_expired(&10), 0);
+        env.ledger().set_timestamp(102 * EPOCH_LENGTH);
+        assert_eq!(client.sweep_expired(&10), 1);
+        assert_eq!(client.sweep_expired(&10), 0);
+        assert_eq!(client.get_available_capacity(), 20_000_000);
+        assert_eq!(client.get_active_staker_count(), 1);
+        assert!(!client.get_stake(&short_staker).unwrap().is_active);
+
+        // Releasing the old allocation does not touch pool totals
+        client.release_capacity(&short_staker, &agent, &1_000_000);
+        assert_eq!(client.get_total_allocated(), 0);
+    }
+
+    #[test]
+    fn test_update_stake() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let agent = Address::generate(&env);
+        let staker = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+
+        env.mock_all_auths();
+        let time_bound = env.ledger().timestamp() + 86400;
+        client.stake_identity(&staker, &10_000_000, &time_bound);
+        client.reserve_capacity(&staker, &agent, &4_000_000, &3600);
+
+        // Raise the limit
+        client.update_stake(&staker, &staker, &20_000_000, &time_bound);
+        assert_eq!(client.get_available_capacity(), 16_000_000);
+
+        // Cannot lower below what is allocated
+        let result = client.try_update_stake(&staker, &staker, &3_000_000, &time_bound);
+        assert!(result.is_err());
+
+        // Lower down to free capacity and extend
+        client.update_stake(&staker, &staker, &5_000_000, &(time_bound + 86400));
+        assert_eq!(client.get_available_capacity(), 1_000_000);
+
+        let stake = client.get_stake(&staker).unwrap();
+        assert_eq!(stake.spending_limit, 5_000_000);
+        assert_eq!(stake.time_bound, time_bound + 86400);
+
+        // Time bound cannot be shortened
+        let result = client.try_update_stake(&staker, &staker, &5_000_000, &time_bound);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_restake_keeps_history() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let staker = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+
+        env.mock_all_auths();
+        let token = fund_fees(&env, &client, &admin, 500_000);
+        env.ledger().set_timestamp(1_000);
+        client.stake_identity(&staker, &10_000_000, &(1_000 + 86400));
+        client.add_fees(&staker, &500_000, &BytesN::from_array(&env, &[0; 32]));
+
+        env.ledger().set_timestamp(5_000);
+        let fees = client.unstake_identity(&staker);
+        assert_eq!(fees, 500_000);
+        assert_eq!(token.balance(&staker), 500_000);
+
+        // Re-stake after unstaking
+        client.stake_identity(&staker, &20_000_000, &(5_000 + 86400));
+        assert!(client.is_active(&staker));
+        assert_eq!(client.get_available_capacity(), 20_000_000);
+
+        let stake = client.get_stake(&staker).unwrap();
+        assert_eq!(stake.accumulated_fees, 0);
+        assert_eq!(stake.total_fees_earned, 0);
+
+        // Previous period is archived
+        assert_eq!(client.get_stake_history_count(&staker), 1);
+        let period = client.get_stake_history(&staker, &0, &10).get(0).unwrap();
+        assert_eq!(period.started_at, 1_000);
+        assert_eq!(period.ended_at, 5_000);
+        assert_eq!(period.spending_limit, 10_000_000);
+        assert_eq!(period.fees_earned, 500_000);
+
+        // Staking twice while active still fails
+        let result = client.try_stake_identity(&staker, &20_000_000, &(5_000 + 86400));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_unbonding_queue() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let agent = Address::generate(&env);
+        let staker = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+
+        env.mock_all_auths();
+        let time_bound = env.ledger().timestamp() + 86400;
+        client.stake_identity(&staker, &10_000_000, &time_bound);
+        client.reserve_capacity(&staker, &agent, &4_000_000, &3600);
+
+        // Unstaking pulls capacity at once but the stake waits for its rails
+        client.unstake_identity(&staker);
+        assert_eq!(client.get_available_capacity(), 0);
+        assert!(client.try_reserve_capacity(&staker, &agent, &1_000_000, &3600).is_err());
+        assert_eq!(client.get_unbonding_count(), 1);
+        assert_eq!(client.get_unbonding_queue(&0, &10).get(0).unwrap(), staker);
+
+        // Cannot complete or re-stake while capacity is still backing rails
+        assert!(client.try_complete_unstake(&staker).is_err());
+        assert!(client.try_stake_identity(&staker, &10_000_000, &time_bound).is_err());
+
+        client.release_capacity(&staker, &agent, &4_000_000);
+        client.complete_unstake(&staker);
+        assert_eq!(client.get_unbonding_count(), 0);
+        assert!(client.get_stake(&staker).unwrap().unbonding_since.is_none());
+
+        // Stakes without allocations never enter the queue
+        let other = Address::generate(&env);
+        sbt.set_level(&other, &2);
+        client.stake_identity(&other, &10_000_000, &time_bound);
+        client.unstake_identity(&other);
+        assert_eq!(client.get_unbonding_count(), 0);
+    }
+
+    #[test]
+    fn test_slash_collateral() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+
+        let admin = Address::generate(&env);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+        let dharma_pool = Address::generate(&env);
+        let agent = Address::generate(&env);
+        let insurance_fund = Address::generate(&env);
+        let staker = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+
+        env.mock_all_auths();
+        let token_address = env.register_stellar_asset_contract_v2(admin.clone()).address();
+        let token = TokenClient::new(&env, &token_address);
+        StellarAssetClient::new(&env, &token_address).mint(&staker, &50_000_000);
+        client.set_collateral_token(&token_address);
+        client.set_insurance_fund(&Some(insurance_fund.clone()));
+
+        // 50% collateral: the 10 USDC limit is backed by 5 USDC
+        let mut config = client.get_config();
+        config.collateral_ratio_bps = 5000;
+        client.set_config(&config);
+        client.stake_identity(&staker, &100_000_000, &(env.ledger().timestamp() + 86400));
+        client.reserve_capacity(&staker, &agent, &40_000_000, &3600);
+
+        // Only the Dharma Pool or the arbiter may slash
+        let reason = String::from_str(&env, "Agent exceeded mandate");
+        let result = client.try_slash(&staker, &staker, &2_000_000, &reason);
+        assert!(result.is_err());
+
+        // The limit falls to what the remaining collateral supports
+        client.slash(&dharma_pool, &staker, &10_000_000, &reason);
+        assert_eq!(token.balance(&insurance_fund), 10_000_000);
+        let stake = client.get_stake(&staker).unwrap();
+        assert_eq!(stake.collateral, 40_000_000);
+        assert_eq!(stake.spending_limit, 80_000_000);
+        assert_eq!(client.get_available_capacity(), 40_000_000);
+
+        // Capacity backing live rails is not pulled; the uncovered part is a shortfall
+        client.slash(&dharma_pool, &staker, &30_000_000, &reason);
+        let stake = client.get_stake(&staker).unwrap();
+        assert_eq!(stake.collateral, 10_000_000);
+        assert_eq!(stake.total_slashed, 40_000_000);
+        assert_eq!(stake.spending_limit, 40_000_000);
+        assert_eq!(client.get_available_capacity(), 0);
+
+        let history = client.get_slash_history(&staker, &0, &10);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.get(0).unwrap().capacity_reduction, 20_000_000);
+        assert_eq!(history.get(0).unwrap().collateral_shortfall, 0);
+        assert_eq!(history.get(1).unwrap().capacity_reduction, 40_000_000);
+        assert_eq!(history.get(1).unwrap().collateral_shortfall, 10_000_000);
+        assert_eq!(history.get(1).unwrap().reason, reason);
+
+        // Remaining collateral comes back once the stake is released
+        client.release_capacity(&staker, &agent, &40_000_000);
+        client.unstake_identity(&staker);
+        assert_eq!(token.balance(&staker), 10_000_000);
+    }
+
+    #[test]
+    fn test_stake_policy() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let staker = Address::generate(&env);
+        let principal = Address::generate(&env);
+        let agent = Address::generate(&env);
+        let other_agent = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+        sbt.set_level(&principal, &2);
+        sbt.set_principal(&agent, &principal);
+        sbt.set_level(&other_agent, &3);
+
+        env.mock_all_auths();
+        client.stake_identity(&staker, &10_000_000, &(env.ledger().timestamp() + 86400));
+        client.set_stake_policy(&staker, &StakePolicy {
+            allowed: vec![&env, principal.clone()],
+            denied: Vec::new(&env),
+            max_exposure_per_agent: 5_000_000,
+            max_rail_duration: 7200,
+            min_agent_tier: 2,
+        });
+
+        // Agent is allowed through its principal and takes the principal's tier
+        client.reserve_capacity(&staker, &agent, &3_000_000, &3600);
+        assert_eq!(client.get_exposure(&staker, &agent), 3_000_000);
+
+        // Exposure and duration caps
+        assert!(client.try_reserve_capacity(&staker, &agent, &3_000_000, &3600).is_err());
+        assert!(client.try_reserve_capacity(&staker, &agent, &1_000_000, &86400).is_err());
+
+        // Not on the allow list
+        assert!(client.try_reserve_capacity(&staker, &other_agent, &1_000_000, &3600).is_err());
+
+        // Tier requirement
+        let mut policy = client.get_stake_policy(&staker).unwrap();
+        policy.allowed = Vec::new(&env);
+        policy.min_agent_tier = 3;
+        client.set_stake_policy(&staker, &policy);
+        assert!(client.try_reserve_capacity(&staker, &agent, &1_000_000, &3600).is_err());
+        client.reserve_capacity(&staker, &other_agent, &1_000_000, &3600);
+
+        // Deny list
+        policy.denied = vec![&env, other_agent.clone()];
+        client.set_stake_policy(&staker, &policy);
+        assert!(client.try_reserve_capacity(&staker, &other_agent, &1_000_000, &3600).is_err());
+
+        client.release_capacity(&staker, &agent, &3_000_000);
+        assert_eq!(client.get_exposure(&staker, &agent), 0);
+    }
+
+    #[test]
+    fn test_operator_delegation() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+
+        let admin = Address::generate(&env);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+        let dharma_pool = Address::generate(&env);
+        let staker = Address::generate(&env);
+        let operator = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+
+        env.mock_all_auths();
+        let token = fund_fees(&env, &client, &admin, 1_000_000);
+        let time_bound = env.ledger().timestamp() + 86400;
+        client.stake_identity(&staker, &10_000_000, &time_bound);
+        client.add_fees(&staker, &1_000_000, &BytesN::from_array(&env, &[0; 32]));
+
+        // Not yet authorized
+        assert!(client.try_update_stake(&operator, &staker, &20_000_000, &time_bound).is_err());
+
+        client.set_operator(&staker, &operator);
+        assert_eq!(client.get_operator(&staker), Some(operator.clone()));
+
+        client.update_stake(&operator, &staker, &20_000_000, &(time_bound + 86400));
+        assert_eq!(client.get_stake(&staker).unwrap().spending_limit, 20_000_000);
+
+        let claimed = client.claim_earnings(&operator, &staker);
+        assert_eq!(claimed, 1_000_000);
+        assert_eq!(token.balance(&staker), 1_000_000);
+
+        // Revocation takes effect immediately
+        client.revoke_operator(&staker);
+        assert!(client.try_claim_earnings(&operator, &staker).is_err());
+    }
+
+    #[test]
+    fn test_staking_config() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+
+        let admin = Address::generate(&env);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+        let dharma_pool = Address::generate(&env);
+        let staker = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+
+        env.mock_all_auths();
+        let token_address = env.register_stellar_asset_contract_v2(admin.clone()).address();
+        let token = TokenClient::new(&env, &token_address);
+        StellarAssetClient::new(&env, &token_address).mint(&staker, &10_000_000);
+        client.set_collateral_token(&token_address);
+
+        let mut config = client.get_config();
+        assert_eq!(config.max_spending_limit, 10_000_000_000_000);
+
+        // Lower the ceiling, require 20% collateral and at least a day
+        config.max_spending_limit = 200_000_000;
+        config.min_time_bound = 86400;
+        config.collateral_ratio_bps = 2000;
+        client.set_config(&config);
+        let (emitter, topics, _) = env.events().all().last().unwrap();
+        assert_eq!(emitter, contract_id);
+        assert_eq!(topics, vec![&env, symbol_short!("config").into_val(&env)]);
+
+        let now = env.ledger().timestamp();
+        assert!(client.try_stake_identity(&staker, &40_000_000, &(now + 3600)).is_err());
+        client.stake_identity(&staker, &40_000_000, &(now + 86400));
+
+        // Required collateral is pulled at stake time
+        assert_eq!(client.get_stake(&staker).unwrap().collateral, 8_000_000);
+        assert_eq!(token.balance(&staker), 2_000_000);
+
+        // Raising the limit needs collateral to be deposited first
+        assert!(client.try_update_stake(&staker, &staker, &50_000_000, &(now + 86400)).is_err());
+        client.deposit_collateral(&staker, &2_000_000);
+        client.update_stake(&staker, &staker, &50_000_000, &(now + 86400));
+
+        // Inconsistent bounds are rejected
+        config.min_spending_limit = 300_000_000;
+        assert!(client.try_set_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_auto_compound() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+
+        let admin = Address::generate(&env);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+        let dharma_pool = Address::generate(&env);
+        let staker = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+
+        env.mock_all_auths();
+        client.stake_identity(&staker, &99_995_000_000, &(env.ledger().timestamp() + 30 * 86400));
+
+        // Compounded fees become withdrawable collateral, so only the Dharma Pool may credit them
+        env.set_auths(&[]);
+        assert!(client.try_add_fees(&staker, &2_000_000, &BytesN::from_array(&env, &[0; 32])).is_err());
+        env.mock_all_auths();
+        client.add_fees(&staker, &2_000_000, &BytesN::from_array(&env, &[0; 32]));
+
+        // Opt-in only
+        assert!(client.try_compound_earnings(&staker).is_err());
+        client.set_auto_compound(&staker, &staker, &true);
+
+        // Not before the interval has passed
+        assert!(client.try_compound_earnings(&staker).is_err());
+        env.ledger().set_timestamp(env.ledger().timestamp() + 86400);
+
+        let converted = client.compound_earnings(&staker);
+        assert_eq!(converted, 2_000_000);
+        let stake = client.get_stake(&staker).unwrap();
+        assert_eq!(stake.spending_limit, 99_997_000_000);
+        assert_eq!(stake.collateral, 2_000_000);
+        assert_eq!(stake.accumulated_fees, 0);
+        assert_eq!(client.get_available_capacity(), 99_997_000_000);
+
+        // Capped at the maximum limit; the rest stays claimable
+        client.add_fees(&staker, &5_000_000, &BytesN::from_array(&env, &[0; 32]));
+        env.ledger().set_timestamp(env.ledger().timestamp() + 86400);
+        assert_eq!(client.compound_earnings(&staker), 3_000_000);
+        let stake = client.get_stake(&staker).unwrap();
+        assert_eq!(stake.spending_limit, 100_000_000_000);
+        assert_eq!(stake.accumulated_fees, 2_000_000);
+    }
+
+    #[test]
+    fn test_kyc_tier_limits() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let staker = Address::generate(&env);
+        let unverified = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &1);
+
+        env.mock_all_auths();
+        let time_bound = env.ledger().timestamp() + 86400;
+
+        // No SBT, no stake
+        assert!(client.try_stake_identity(&unverified, &1_000_000, &time_bound).is_err());
+
+        // Basic tier is capped at 1,000 USDC
+        assert!(client.try_stake_identity(&staker, &10_000_000_001, &time_bound).is_err());
+        client.stake_identity(&staker, &10_000_000_000, &time_bound);
+        assert_eq!(client.get_stake(&staker).unwrap().tier, 1);
+        assert!(client.try_update_stake(&staker, &staker, &20_000_000_000, &time_bound).is_err());
+
+        // An upgraded identity can grow up to the enhanced cap of 10,000 USDC
+        sbt.set_level(&staker, &2);
+        client.update_stake(&staker, &staker, &100_000_000_000, &time_bound);
+        assert_eq!(client.get_stake(&staker).unwrap().tier, 2);
+        assert!(client.try_update_stake(&staker, &staker, &100_000_000_001, &time_bound).is_err());
+
+        // Institutional identities reach 1,000,000 USDC
+        sbt.set_level(&staker, &3);
+        client.update_stake(&staker, &staker, &10_000_000_000_000, &time_bound);
+        assert!(client.try_update_stake(&staker, &staker, &10_000_000_000_001, &time_bound).is_err());
+
+        // Tier caps are admin-configurable
+        let mut config = client.get_config();
+        config.tier_limits = vec![&env, 5_000_000];
+        client.set_config(&config);
+        assert!(client.try_update_stake(&staker, &staker, &10_000_000_000_000, &(time_bound + 1)).is_err());
+    }
+
+    #[test]
+    fn test_allocate_backers() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let agent = Address::generate(&env);
+        let small = Address::generate(&env);
+        let medium = Address::generate(&env);
+        let large = Address::generate(&env);
+        let picky = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        for staker in [&small, &medium, &large, &picky] {
+            sbt.set_level(staker, &2);
+        }
+
+        env.mock_all_auths();
+        let time_bound = env.ledger().timestamp() + 86400;
+        client.stake_identity(&small, &10_000_000, &time_bound);
+        client.stake_identity(&medium, &20_000_000, &time_bound);
+        client.stake_identity(&large, &30_000_000, &time_bound);
+        client.stake_identity(&picky, &50_000_000, &time_bound);
+        client.set_stake_policy(&picky, &StakePolicy {
+            allowed: Vec::new(&env),
+            denied: vec![&env, agent.clone()],
+            max_exposure_per_agent: 0,
+            max_rail_duration: 0,
+            min_agent_tier: 0,
+        });
+
+        // Split pro-rata over free capacity, skipping stakes whose policy rejects the agent
+        let backings = client.allocate(&6_000_000, &agent, &3600);
+        assert_eq!(backings.len(), 3);
+        assert_eq!(backings.get(0).unwrap().staker, small);
+        assert_eq!(backings.get(0).unwrap().amount, 1_000_000);
+        assert_eq!(backings.get(1).unwrap().amount, 2_000_000);
+        assert_eq!(backings.get(2).unwrap().amount, 3_000_000);
+        assert_eq!(client.get_exposure(&large, &agent), 3_000_000);
+        assert_eq!(client.get_total_allocated(), 6_000_000);
+
+        // Stakes expiring before the rail ends cannot back it, nor can more than exists
+        assert!(client.try_allocate(&1_000_000, &agent, &(2 * 86400)).is_err());
+        assert!(client.try_allocate(&60_000_000, &agent, &3600).is_err());
+
+        // No stake takes more than the concentration cap; dust goes in scan order
+        let mut config = client.get_config();
+        config.max_backer_share_bps = 4000;
+        client.set_config(&config);
+        let capped = client.allocate(&10_000_001, &agent, &3600);
+        let mut total = 0;
+        for backing in capped.iter() {
+            assert!(backing.amount <= 4_000_000);
+            total += backing.amount;
+        }
+        assert_eq!(total, 10_000_001);
+
+        // Releasing restores the capacity
+        client.release_allocation(&agent, &backings);
+        client.release_allocation(&agent, &capped);
+        assert_eq!(client.get_total_allocated(), 0);
+        assert_eq!(client.get_stake(&small).unwrap().allocated, 0);
+    }
+
+    #[test]
+    fn test_allocation_scans_past_candidates() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let agent = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+
+        env.mock_all_auths();
+        let time_bound = env.ledger().timestamp() + 86400;
+        for _ in 0..MAX_ALLOCATION_CANDIDATES {
+            let staker = Address::generate(&env);
+            sbt.set_level(&staker, &2);
+            client.stake_identity(&staker, &1_000_000, &time_bound);
+        }
+        let large = Address::generate(&env);
+        sbt.set_level(&large, &2);
+        client.stake_identity(&large, &50_000_000, &time_bound);
+
+        // The first candidates cannot cover the rail, so the scan reaches the large stake
+        let backings = client.allocate(&30_000_000, &agent, &3600);
+        assert_eq!(backings.len(), MAX_ALLOCATION_CANDIDATES + 1);
+        assert_eq!(client.get_stake(&large).unwrap().allocated, 30_000_000 * 50 / 70);
+        assert_eq!(client.get_total_allocated(), 30_000_000);
+    }
+
+    #[test]
+    fn test_earnings_ledger() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let staker = Address::generate(&env);
+        let first_rail = BytesN::from_array(&env, &[1; 32]);
+        let second_rail = BytesN::from_array(&env, &[2; 32]);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+
+        env.mock_all_auths();
+        fund_fees(&env, &client, &admin, 650);
+        env.ledger().set_timestamp(1_000);
+        client.stake_identity(&staker, &10_000_000, &(1_000 + 30 * 86400));
+        client.add_fees(&staker, &300, &first_rail);
+
+        env.ledger().set_timestamp(2_000);
+        client.add_fees(&staker, &200, &second_rail);
+        client.claim_earnings(&staker, &staker);
+
+        env.ledger().set_timestamp(3_000);
+        client.add_fees(&staker, &50, &first_rail);
+
+        // Every credit names its rail; claims are recorded too
+        assert_eq!(client.get_earnings_ledger_count(&staker), 4);
+        let entries = client.get_earnings_ledger(&staker, &1, &2);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries.get(0).unwrap().kind, LedgerKind::Credit);
+        assert_eq!(entries.get(0).unwrap().rail_id, Some(second_rail));
+        assert_eq!(entries.get(0).unwrap().timestamp, 2_000);
+        assert_eq!(entries.get(1).unwrap().kind, LedgerKind::Claim);
+        assert_eq!(entries.get(1).unwrap().amount, 500);
+        assert_eq!(entries.get(1).unwrap().rail_id, None);
+
+        // Period totals
+        assert_eq!(
+            client.get_earnings_totals(&staker, &2_000, &3_000),
+            EarningsTotals { credited: 200, claimed: 500, compounded: 0, vaulted: 0 }
+        );
+        assert_eq!(
+            client.get_earnings_totals(&staker, &0, &u64::MAX),
+            EarningsTotals { credited: 550, claimed: 500, compounded: 0, vaulted: 0 }
+        );
+        assert_eq!(client.get_earnings_totals(&staker, &5_000, &6_000).credited, 0);
+
+        // Once tokenized, unclaimed and new fees go to the share holders, not the staker
+        env.ledger().set_timestamp(7_000);
+        client.tokenize_stake(&staker);
+        client.add_fees(&staker, &100, &first_rail);
+        let entries = client.get_earnings_ledger(&staker, &4, &2);
+        assert_eq!(entries.get(0).unwrap().kind, LedgerKind::Tokenized);
+        assert_eq!(entries.get(0).unwrap().amount, 50);
+        assert_eq!(entries.get(1).unwrap().kind, LedgerKind::VaultCredit);
+        assert_eq!(
+            client.get_earnings_totals(&staker, &7_000, &u64::MAX),
+            EarningsTotals { credited: 0, claimed: 0, compounded: 0, vaulted: 150 }
+        );
+    }
+
+    #[test]
+    fn test_share_token() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let staker = Address::generate(&env);
+        let treasury = Address::generate(&env);
+        let rail = BytesN::from_array(&env, &[1; 32]);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+
+        env.mock_all_auths();
+        let token_address = env.register_stellar_asset_contract_v2(admin.clone()).address();
+        let token = TokenClient::new(&env, &token_address);
+        StellarAssetClient::new(&env, &token_address).mint(&staker, &2_000_000);
+        client.set_collateral_token(&token_address);
+
+        let mut config = client.get_config();
+        config.collateral_ratio_bps = 2000;
+        client.set_config(&config);
+
+        let time_bound = env.ledger().timestamp() + 86400;
+        client.stake_identity(&staker, &10_000_000, &time_bound);
+
+        // Fees are paid into the pool in the collateral token
+        StellarAssetClient::new(&env, &token_address).mint(&contract_id, &1_000_000);
+        client.add_fees(&staker, &500_000, &rail);
+
+        // Collateral plus unclaimed fees become shares
+        assert_eq!(client.tokenize_stake(&staker), 2_500_000);
+        assert_eq!(client.share_balance(&staker, &staker), 2_500_000);
+        assert_eq!(client.get_stake(&staker).unwrap().accumulated_fees, 0);
+
+        // Shares move like a SEP-41 token while the stake stays put
+        client.transfer_shares(&staker, &staker, &treasury, &1_000_000);
+        assert_eq!(client.share_balance(&staker, &treasury), 1_000_000);
+        assert!(client.get_stake(&staker).unwrap().is_active);
+
+        // Later fees raise the value of every share of the stake
+        client.add_fees(&staker, &500_000, &rail);
+        assert_eq!(client.get_vault(&staker), ShareVault { assets: 3_000_000, liquid: 1_000_000, shares: 2_500_000 });
+        assert_eq!(client.redeem(&treasury, &staker, &500_000), 600_000);
+        assert_eq!(token.balance(&treasury), 600_000);
+
+        // Collateral still backing the stake cannot be redeemed
+        assert!(client.try_redeem(&treasury, &staker, &500_000).is_err());
+
+        // Once unstaked the collateral becomes redeemable
+        client.unstake_identity(&staker);
+        assert_eq!(client.redeem(&treasury, &staker, &500_000), 600_000);
+        assert_eq!(client.redeem(&staker, &staker, &1_500_000), 1_800_000);
+        assert_eq!(client.get_vault(&staker), ShareVault { assets: 0, liquid: 0, shares: 0 });
+    }
+
+    #[test]
+    fn test_share_classes() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let earner = Address::generate(&env);
+        let offender = Address::generate(&env);
+        let rail = BytesN::from_array(&env, &[1; 32]);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&earner, &2);
+        sbt.set_level(&offender, &2);
+
+        env.mock_all_auths();
+        let token_address = env.register_stellar_asset_contract_v2(admin.clone()).address();
+        let minter = StellarAssetClient::new(&env, &token_address);
+        minter.mint(&earner, &2_000_000);
+        minter.mint(&offender, &2_000_000);
+        client.set_collateral_token(&token_address);
+
+        let mut config = client.get_config();
+        config.collateral_ratio_bps = 2000;
+        client.set_config(&config);
+
+        let time_bound = env.ledger().timestamp() + 86400;
+        client.stake_identity(&earner, &10_000_000, &time_bound);
+        client.stake_identity(&offender, &10_000_000, &time_bound);
+        assert_eq!(client.tokenize_stake(&earner), 2_000_000);
+        assert_eq!(client.tokenize_stake(&offender), 2_000_000);
+
+        // Each stake's fees and slashes land only on its own shares
+        minter.mint(&contract_id, &400_000);
+        client.add_fees(&earner, &400_000, &rail);
+        client.slash(&dharma_pool, &offender, &400_000, &String::from_str(&env, "fraud"));
+        assert_eq!(client.get_vault(&earner).assets, 2_400_000);
+        assert_eq!(client.get_vault(&offender).assets, 1_600_000);
+
+        // New collateral buys shares at the stake's own price
+        minter.mint(&offender, &400_000);
+        client.deposit_collateral(&offender, &400_000);
+        assert_eq!(client.share_balance(&offender, &offender), 2_500_000);
+
+        // A vault slashed to nothing takes no deposits its stale shares would dilute
+        client.slash(&dharma_pool, &offender, &2_000_000, &String::from_str(&env, "fraud"));
+        assert_eq!(client.get_vault(&offender), ShareVault { assets: 0, liquid: 0, shares: 2_500_000 });
+        minter.mint(&offender, &400_000);
+        assert_eq!(client.try_deposit_collateral(&offender, &400_000), Err(Ok(PoolError::VaultDepleted)));
+
+        client.unstake_identity(&earner);
+        assert_eq!(client.redeem(&earner, &earner, &2_000_000), 2_400_000);
+        assert_eq!(client.get_vault(&earner), ShareVault { assets: 0, liquid: 0, shares: 0 });
+    }
+
+    #[test]
+    fn test_emergency_pause() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let pauser = Address::generate(&env);
+        let staker = Address::generate(&env);
+        let late_staker = Address::generate(&env);
+        let rail = BytesN::from_array(&env, &[1; 32]);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+        sbt.set_level(&late_staker, &2);
+
+        env.mock_all_auths();
+        let token = fund_fees(&env, &client, &admin, 1_000);
+        let time_bound = env.ledger().timestamp() + 86400;
+        client.stake_identity(&staker, &10_000_000, &time_bound);
+        client.add_fees(&staker, &1_000, &rail);
+
+        // Only the admin and pauser may pause
+        let all = PauseFlags { staking: true, claiming: true, fee_intake: true };
+        assert!(client.try_set_paused(&staker, &all).is_err());
+        client.set_pauser(&Some(pauser.clone()));
+        client.set_paused(&pauser, &all);
+        assert_eq!(client.get_paused(), all);
+
+        assert!(client.try_stake_identity(&late_staker, &10_000_000, &time_bound).is_err());
+        assert!(client.try_add_fees(&staker, &1_000, &rail).is_err());
+        assert!(client.try_claim_earnings(&staker, &staker).is_err());
+
+        // Reads still work and stakers can still get out, leaving their fees behind
+        assert_eq!(client.get_available_capacity(), 10_000_000);
+        assert_eq!(client.unstake_identity(&staker), 0);
+        assert!(!client.is_active(&staker));
+        assert_eq!(client.get_stake(&staker).unwrap().accumulated_fees, 1_000);
+        assert_eq!(token.balance(&staker), 0);
+
+        // The pauser cannot unpause; the admin can, flag by flag
+        let staking_only = PauseFlags { staking: true, claiming: false, fee_intake: false };
+        assert!(client.try_set_paused(&pauser, &staking_only).is_err());
+        client.set_paused(&admin, &staking_only);
+        assert!(client.try_stake_identity(&late_staker, &10_000_000, &time_bound).is_err());
+        assert_eq!(client.claim_earnings(&staker, &staker), 1_000);
+        assert_eq!(token.balance(&staker), 1_000);
+    }
+
+    #[test]
+    fn test_enforce_identity() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+        let dharma_pool = env.register(MockDharma, ());
+        let dharma = MockDharmaClient::new(&env, &dharma_pool);
+
+        let admin = Address::generate(&env);
+        let agent = Address::generate(&env);
+        let staker = Address::generate(&env);
+        let rail = BytesN::from_array(&env, &[1; 32]);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+
+        env.mock_all_auths();
+        let token = fund_fees(&env, &client, &admin, 1_000);
+        let time_bound = env.ledger().timestamp() + 86400;
+        client.stake_identity(&staker, &10_000_000, &time_bound);
+        client.reserve_capacity(&staker, &agent, &4_000_000, &3600);
+        client.add_fees(&staker, &1_000, &rail);
+
+        // A valid identity cannot be ejected
+        assert!(client.try_enforce_identity(&staker).is_err());
+
+        // Once the SBT is revoked anyone can eject the staker
+        sbt.set_level(&staker, &0);
+        client.enforce_identity(&staker);
+        assert!(dharma.is_flagged(&staker));
+        assert_eq!(client.get_available_capacity(), 0);
+        assert!(!client.is_active(&staker));
+        assert_eq!(client.get_unbonding_count(), 1);
+
+        // Earnings and collateral stay frozen until reviewed
+        assert!(client.try_claim_earnings(&staker, &staker).is_err());
+        client.release_capacity(&staker, &agent, &4_000_000);
+        assert!(client.try_complete_unstake(&staker).is_err());
+        assert!(client.try_enforce_identity(&staker).is_err());
+
+        client.unfreeze_stake(&staker);
+        assert!(!dharma.is_flagged(&staker));
+        client.complete_unstake(&staker);
+        assert_eq!(client.claim_earnings(&staker, &staker), 1_000);
+        assert_eq!(token.balance(&staker), 1_000);
+    }
+
+    #[test]
+    fn test_enforce_identity_freezes_shares() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+        let dharma_pool = env.register(MockDharma, ());
+
+        let admin = Address::generate(&env);
+        let staker = Address::generate(&env);
+        let buyer = Address::generate(&env);
+        let rail = BytesN::from_array(&env, &[1; 32]);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&staker, &2);
+
+        env.mock_all_auths();
+        let token = fund_fees(&env, &client, &admin, 500_000);
+        StellarAssetClient::new(&env, &token.address).mint(&staker, &2_000_000);
+        let mut config = client.get_config();
+        config.collateral_ratio_bps = 2000;
+        client.set_config(&config);
+
+        client.stake_identity(&staker, &10_000_000, &(env.ledger().timestamp() + 86400));
+        client.add_fees(&staker, &500_000, &rail);
+        assert_eq!(client.tokenize_stake(&staker), 2_500_000);
+        client.transfer_shares(&staker, &staker, &buyer, &500_000);
+
+        // The ejected staker's shares are frozen like its unclaimed fees
+        sbt.set_level(&staker, &0);
+        client.enforce_identity(&staker);
+        assert_eq!(client.try_redeem(&staker, &staker, &100_000), Err(Ok(PoolError::StakeFrozen)));
+        assert!(client.try_transfer_shares(&staker, &staker, &buyer, &100_000).is_err());
+        assert!(client.try_burn_shares(&staker, &staker, &100_000).is_err());
+
+        // Shares already sold are not the staker's to freeze
+        assert_eq!(client.redeem(&buyer, &staker, &500_000), 500_000);
+
+        client.unfreeze_stake(&staker);
+        client.transfer_shares(&staker, &staker, &buyer, &100_000);
+        assert_eq!(client.share_balance(&staker, &buyer), 100_000);
+    }
+
+    #[test]
+    fn test_reallocate() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let agent = Address::generate(&env);
+        let leaving = Address::generate(&env);
+        let staying = Address::generate(&env);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&leaving, &2);
+        sbt.set_level(&staying, &2);
+
+        env.mock_all_auths();
+        let time_bound = env.ledger().timestamp() + 86400;
+        client.stake_identity(&leaving, &10_000_000, &time_bound);
+        client.reserve_capacity(&leaving, &agent, &4_000_000, &3600);
+        client.stake_identity(&staying, &10_000_000, &time_bound);
+
+        // The backing moves to other stakes only
+        let backings = client.reallocate(&agent, &leaving, &4_000_000, &3600);
+        assert_eq!(backings.len(), 1);
+        assert_eq!(backings.get(0).unwrap().staker, staying);
+        assert_eq!(client.get_exposure(&leaving, &agent), 0);
+        assert_eq!(client.get_exposure(&staying, &agent), 4_000_000);
+        assert_eq!(client.get_total_allocated(), 4_000_000);
+
+        // Only what the staker actually backs can be moved
+        assert!(client.try_reallocate(&agent, &leaving, &1_000_000, &3600).is_err());
+    }
+
+    #[test]
+    fn test_distribute_fees() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let small = Address::generate(&env);
+        let large = Address::generate(&env);
+        let rail = BytesN::from_array(&env, &[1; 32]);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&small, &2);
+        sbt.set_level(&large, &2);
+
+        env.mock_all_auths();
+        let time_bound = env.ledger().timestamp() + 86400;
+        client.stake_identity(&small, &10_000_000, &time_bound);
+        client.stake_identity(&large, &20_000_000, &time_bound);
+
+        // 100 split 1:2 leaves one unit of dust, which goes to the larger backer
+        let backings = vec![
+            &env,
+            Backing { staker: small.clone(), amount: 1_000_000 },
+            Backing { staker: large.clone(), amount: 2_000_000 },
+        ];
+        client.distribute_fees(&rail, &100, &backings);
+        assert_eq!(client.get_stake(&small).unwrap().accumulated_fees, 33);
+        assert_eq!(client.get_stake(&large).unwrap().accumulated_fees, 67);
+        assert_eq!(client.get_earnings_ledger(&large, &0, &10).get(0).unwrap().rail_id, Some(rail.clone()));
+
+        assert!(client.try_distribute_fees(&rail, &100, &Vec::new(&env)).is_err());
+
+        let fee_intake = PauseFlags { staking: false, claiming: false, fee_intake: true };
+        client.set_paused(&admin, &fee_intake);
+        assert!(client.try_distribute_fees(&rail, &100, &backings).is_err());
+    }
+
+    #[test]
+    fn test_distribute_fees_skips_departed_backers() {
+        let env = Env::default();
+        let contract_id = env.register(IdentityPoolContract, ());
+        let client = IdentityPoolContractClient::new(&env, &contract_id);
+        let sbt_contract = env.register(MockSbt, ());
+        let sbt = MockSbtClient::new(&env, &sbt_contract);
+
+        let admin = Address::generate(&env);
+        let dharma_pool = Address::generate(&env);
+        let small = Address::generate(&env);
+        let large = Address::generate(&env);
+        let rail = BytesN::from_array(&env, &[1; 32]);
+
+        client.initialize(&admin, &sbt_contract, &dharma_pool);
+        sbt.set_level(&small, &2);
+        sbt.set_level(&large, &2);
+
+        env.mock_all_auths();
+        fund_fees(&env, &client, &admin, 100);
+        let time_bound = env.ledger().timestamp() + 86400;
+        client.stake_identity(&small, &10_000_000, &time_bound);
+        client.stake_identity(&large, &20_000_000, &time_bound);
+        let backings = vec![
+            &env,
+            Backing { staker: small.clone(), amount: 1_000_000 },
+            Backing { staker: large.clone(), amount: 2_000_000 },
+        ];
+
+        // A backer leaves while the rail's fees are held back
+        let fee_intake = PauseFlags { staking: false, claiming: false, fee_intake: true };
+        client.set_paused(&admin, &fee_intake);
+        assert_eq!(client.try_distribute_fees(&rail, &100, &backings), Err(Ok(PoolError::FeeIntakePaused)));
+        client.unstake_identity(&large);
+        client.set_paused(&admin, &PauseFlags { staking: false, claiming: false, fee_intake: false });
+
+        // The remaining backer takes the whole amount
+        client.distribute_fees(&rail, &100, &backings);
+        assert_eq!(client.get_stake(&small).unwrap().accumulated_fees, 100);
+        assert_eq!(client.get_stake(&large).unwrap().accumulated_fees, 0);
+
+        // With every backer gone there is nobody to credit
+        client.unstake_identity(&small);
+        assert_eq!(client.try_distribute_fees(&rail, &100, &backings), Err(Ok(PoolError::NoBackers)));
     }
 }
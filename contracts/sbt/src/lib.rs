@@ -1,5 +1,5 @@
 #![no_std]
-use soroban_sdk::{contract, contractimpl, contracttype, Address, BytesN, Env, String};
+use soroban_sdk::{contract, contracterror, contractimpl, contracttype, Address, BytesN, Env};
 
 #[contracttype]
 #[derive(Clone)]
@@ -14,8 +14,27 @@ pub struct SBT {
 pub enum DataKey {
     SBT(Address),
     Admin,
+    Principal(Address),  // Agent -> SBT owner it acts for
+    AssuranceLevel(Address), // Kept out of SBT so issued tokens still decode; unset means basic
 }
 
+#[contracterror]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum SbtError {
+    AlreadyIssued = 1,
+    NotFound = 2,
+    NotTransferable = 3,
+    InvalidAssuranceLevel = 4,
+    AgentAlreadyRegistered = 5,
+    AgentNotRegistered = 6,
+}
+
+// Assurance levels (KYC tiers)
+pub const ASSURANCE_BASIC: u32 = 1;
+pub const ASSURANCE_ENHANCED: u32 = 2;
+pub const ASSURANCE_INSTITUTIONAL: u32 = 3;
+
 #[contract]
 pub struct SBTContract;
 
@@ -31,12 +50,12 @@ impl SBTContract {
 
     /// Issue a new SBT to a user
     /// For demo purposes, anyone can issue (in production, only authorized anchors)
-    pub fn issue_sbt(env: Env, owner: Address, kyc_hash: BytesN<32>) -> Result<(), String> {
+    pub fn issue_sbt(env: Env, owner: Address, kyc_hash: BytesN<32>) -> Result<(), SbtError> {
         owner.require_auth();
 
         // Check if SBT already exists
         if env.storage().persistent().has(&DataKey::SBT(owner.clone())) {
-            return Err(String::from_str(&env, "SBT already exists"));
+            return Err(SbtError::AlreadyIssued);
         }
 
         let sbt = SBT {
@@ -70,8 +89,66 @@ impl SBTContract {
         Self::verify_sbt(env, owner)
     }
 
+    /// Set the assurance level of an SBT after stronger verification (admin only)
+    pub fn set_assurance_level(env: Env, owner: Address, level: u32) -> Result<(), SbtError> {
+        let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+        admin.require_auth();
+
+        if !(ASSURANCE_BASIC..=ASSURANCE_INSTITUTIONAL).contains(&level) {
+            return Err(SbtError::InvalidAssuranceLevel);
+        }
+
+        if !env.storage().persistent().has(&DataKey::SBT(owner.clone())) {
+            return Err(SbtError::NotFound);
+        }
+        env.storage().persistent().set(&DataKey::AssuranceLevel(owner), &level);
+        Ok(())
+    }
+
+    /// Get the assurance level of a valid SBT (0 if missing or revoked)
+    pub fn get_assurance_level(env: Env, owner: Address) -> u32 {
+        if !Self::verify_sbt(env.clone(), owner.clone()) {
+            return 0;
+        }
+        env.storage().persistent().get(&DataKey::AssuranceLevel(owner)).unwrap_or(ASSURANCE_BASIC)
+    }
+
+    /// Register an agent address acting on behalf of an SBT holder (both must sign)
+    pub fn register_agent(env: Env, owner: Address, agent: Address) -> Result<(), SbtError> {
+        owner.require_auth();
+        agent.require_auth();
+
+        if !Self::verify_sbt(env.clone(), owner.clone()) {
+            return Err(SbtError::NotFound);
+        }
+        if env.storage().persistent().has(&DataKey::Principal(agent.clone())) {
+            return Err(SbtError::AgentAlreadyRegistered);
+        }
+
+        env.storage().persistent().set(&DataKey::Principal(agent), &owner);
+        Ok(())
+    }
+
+    /// Remove an agent registration
+    pub fn unregister_agent(env: Env, owner: Address, agent: Address) -> Result<(), SbtError> {
+        owner.require_auth();
+
+        match env.storage().persistent().get::<DataKey, Address>(&DataKey::Principal(agent.clone())) {
+            Some(principal) if principal == owner => {
+                env.storage().persistent().remove(&DataKey::Principal(agent));
+                Ok(())
+            }
+            _ => Err(SbtError::AgentNotRegistered),
+        }
+    }
+
+    /// Get the SBT holder an agent acts for
+    pub fn get_principal(env: Env, agent: Address) -> Option<Address> {
+        env.storage().persistent().get(&DataKey::Principal(agent))
+    }
+
     /// Revoke an SBT (for demo, owner can revoke their own)
-    pub fn revoke_sbt(env: Env, owner: Address) -> Result<(), String> {
+    pub fn revoke_sbt(env: Env, owner: Address) -> Result<(), SbtError> {
         owner.require_auth();
 
         if let Some(mut sbt) = env.storage().persistent().get::<DataKey, SBT>(&DataKey::SBT(owner.clone())) {
@@ -79,26 +156,29 @@ impl SBTContract {
             env.storage().persistent().set(&DataKey::SBT(owner), &sbt);
             Ok(())
         } else {
-            Err(String::from_str(&env, "SBT not found"))
+            Err(SbtError::NotFound)
         }
     }
 
     /// Prevent transfer - SBTs are soulbound
     /// This is a placeholder to demonstrate the concept
-    pub fn transfer(env: Env, _from: Address, _to: Address) -> Result<(), String> {
-        Err(String::from_str(&env, "SBTs cannot be transferred"))
+    pub fn transfer(_env: Env, _from: Address, _to: Address) -> Result<(), SbtError> {
+        Err(SbtError::NotTransferable)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use soroban_sdk::{testutils::Address as _, Env};
+    use soroban_sdk::{
+        testutils::{Address as _, MockAuth, MockAuthInvoke},
+        Env, IntoVal,
+    };
 
     #[test]
     fn test_issue_and_verify_sbt() {
         let env = Env::default();
-        let contract_id = env.register_contract(None, SBTContract);
+        let contract_id = env.register(SBTContract, ());
         let client = SBTContractClient::new(&env, &contract_id);
 
         let admin = Address::generate(&env);
@@ -110,7 +190,7 @@ mod test {
 
         // Issue SBT
         env.mock_all_auths();
-        let result = client.issue_sbt(&user, &kyc_hash);
+        let result = client.try_issue_sbt(&user, &kyc_hash);
         assert!(result.is_ok());
 
         // Verify SBT
@@ -127,21 +207,21 @@ mod test {
     #[test]
     fn test_transfer_blocked() {
         let env = Env::default();
-        let contract_id = env.register_contract(None, SBTContract);
+        let contract_id = env.register(SBTContract, ());
         let client = SBTContractClient::new(&env, &contract_id);
 
         let user1 = Address::generate(&env);
         let user2 = Address::generate(&env);
 
         env.mock_all_auths();
-        let result = client.transfer(&user1, &user2);
+        let result = client.try_transfer(&user1, &user2);
         assert!(result.is_err());
     }
 
     #[test]
     fn test_revoke_sbt() {
         let env = Env::default();
-        let contract_id = env.register_contract(None, SBTContract);
+        let contract_id = env.register(SBTContract, ());
         let client = SBTContractClient::new(&env, &contract_id);
 
         let admin = Address::generate(&env);
@@ -151,11 +231,80 @@ mod test {
         client.initialize(&admin);
 
         env.mock_all_auths();
-        client.issue_sbt(&user, &kyc_hash).unwrap();
+        client.issue_sbt(&user, &kyc_hash);
         assert!(client.is_valid(&user));
 
         // Revoke
-        client.revoke_sbt(&user).unwrap();
+        client.revoke_sbt(&user);
         assert!(!client.is_valid(&user));
     }
+
+    #[test]
+    fn test_assurance_level_and_agents() {
+        let env = Env::default();
+        let contract_id = env.register(SBTContract, ());
+        let client = SBTContractClient::new(&env, &contract_id);
+
+        let admin = Address::generate(&env);
+        let user = Address::generate(&env);
+        let agent = Address::generate(&env);
+        let kyc_hash = BytesN::from_array(&env, &[1u8; 32]);
+
+        client.initialize(&admin);
+
+        env.mock_all_auths();
+        client.issue_sbt(&user, &kyc_hash);
+        assert_eq!(client.get_assurance_level(&user), ASSURANCE_BASIC);
+
+        client.set_assurance_level(&user, &ASSURANCE_ENHANCED);
+        assert_eq!(client.get_assurance_level(&user), ASSURANCE_ENHANCED);
+        assert!(client.try_set_assurance_level(&user, &4).is_err());
+
+        // An agent cannot be claimed without its own signature
+        let intruder = Address::generate(&env);
+        client.issue_sbt(&intruder, &kyc_hash);
+        env.mock_auths(&[MockAuth {
+            address: &intruder,
+            invoke: &MockAuthInvoke {
+                contract: &contract_id,
+                fn_name: "register_agent",
+                args: (&intruder, &agent).into_val(&env),
+                sub_invokes: &[],
+            },
+        }]);
+        assert!(client.try_register_agent(&intruder, &agent).is_err());
+        env.mock_all_auths();
+
+        // Agents act for a principal holding a valid SBT
+        client.register_agent(&user, &agent);
+        assert_eq!(client.get_principal(&agent), Some(user.clone()));
+        client.unregister_agent(&user, &agent);
+        assert_eq!(client.get_principal(&agent), None);
+
+        // Revoked SBTs carry no assurance
+        client.revoke_sbt(&user);
+        assert_eq!(client.get_assurance_level(&user), 0);
+        assert!(client.try_register_agent(&user, &agent).is_err());
+    }
+
+    #[test]
+    fn test_sbt_issued_before_assurance_levels() {
+        let env = Env::default();
+        let contract_id = env.register(SBTContract, ());
+        let client = SBTContractClient::new(&env, &contract_id);
+        let user = Address::generate(&env);
+
+        // Tokens issued before levels existed have no level entry and read as basic
+        env.as_contract(&contract_id, || {
+            let sbt = SBT {
+                owner: user.clone(),
+                kyc_hash: BytesN::from_array(&env, &[1u8; 32]),
+                issued_at: 0,
+                is_valid: true,
+            };
+            env.storage().persistent().set(&DataKey::SBT(user.clone()), &sbt);
+        });
+        assert!(client.get_sbt(&user).is_some());
+        assert_eq!(client.get_assurance_level(&user), ASSURANCE_BASIC);
+    }
 }
@@ -8,6 +8,7 @@ import {
   scValToNative,
   Horizon,
   rpc,
+  xdr,
 } from '@stellar/stellar-sdk';
 import { STELLAR_CONFIG } from './stellar-config';
 
@@ -182,10 +183,12 @@ export class ContractClient {
   }
 
   // Dharma Pool Methods
+  // Rails are only issued against a verifier attestation covering this exact request
   async requestCompliance(
     agentAddress: string,
     amount: number,
-    duration: number
+    duration: number,
+    attestation: { expiresAt: number; nonce: number; signature: string }
   ): Promise<string> {
     this.ensureContractsInitialized();
     const account = await server.getAccount(agentAddress);
@@ -196,10 +199,27 @@ export class ContractClient {
     })
       .addOperation(
         this.dharmaPoolContract!.call(
-          'request_compliance',
+          'issue_rail',
           Address.fromString(agentAddress).toScVal(),
           nativeToScVal(amount * 10000000, { type: 'i128' }),
-          nativeToScVal(duration, { type: 'u64' })
+          nativeToScVal(duration, { type: 'u64' }),
+          xdr.ScVal.scvVec([
+            xdr.ScVal.scvSymbol('Attestation'),
+            nativeToScVal(
+              {
+                expires_at: attestation.expiresAt,
+                nonce: attestation.nonce,
+                signature: Buffer.from(attestation.signature, 'hex'),
+              },
+              {
+                type: {
+                  expires_at: ['symbol', 'u64'],
+                  nonce: ['symbol', 'u64'],
+                  signature: ['symbol', 'bytes'],
+                },
+              }
+            ),
+          ])
         )
       )
       .setTimeout(30)
@@ -141,7 +141,9 @@ soroban contract invoke \
   --source deployer \
   --network testnet \
   -- initialize \
-  --sbt_contract $SBT_ID > /dev/null 2>&1
+  --admin $DEPLOYER_ADDRESS \
+  --sbt_contract $SBT_ID \
+  --dharma_pool $DHARMA_ID > /dev/null 2>&1
 
 if [ $? -eq 0 ]; then
     echo "  ✅ Identity Pool initialized"
@@ -155,6 +157,7 @@ soroban contract invoke \
   --source deployer \
   --network testnet \
   -- initialize \
+  --admin $DEPLOYER_ADDRESS \
   --identity_pool $POOL_ID \
   --protocol_treasury $DEPLOYER_ADDRESS > /dev/null 2>&1
 